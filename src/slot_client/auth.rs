@@ -0,0 +1,144 @@
+//! Authentication of join requests
+//!
+//! A module and the Slot server can share a secret for the module's name. The
+//! module then signs every join with HMAC-SHA256 over its name, HTTP port, join
+//! options, the current time and a random nonce. The server rejects joins whose
+//! signature doesn't match, which are too old, or whose nonce it has already
+//! seen, so a captured join can't be sent again.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{JoinOptions, ValidName};
+
+/// A module's pre-shared key. Never sent to the server, only used to sign
+/// joins
+#[derive(Clone, Default, PartialEq)]
+pub struct JoinSecret(Vec<u8>);
+
+impl std::fmt::Debug for JoinSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "JoinSecret(..)")
+    }
+}
+
+impl From<&str> for JoinSecret {
+    fn from(secret: &str) -> Self {
+        Self(secret.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for JoinSecret {
+    fn from(secret: Vec<u8>) -> Self {
+        Self(secret)
+    }
+}
+
+/// Proof that a join was sent by a holder of the module's secret
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JoinAuth {
+    /// Seconds since the Unix epoch when the join was signed
+    pub timestamp: u64,
+
+    /// Random number which makes each signed join unique
+    pub nonce: u64,
+
+    /// HMAC-SHA256 of the join under the module's secret
+    pub mac: Vec<u8>,
+}
+
+impl JoinAuth {
+    /// Sign a join as `name` serving HTTP on `http_port` with `options`
+    pub fn sign(
+        secret: &JoinSecret,
+        name: &ValidName,
+        http_port: u16,
+        options: &JoinOptions,
+    ) -> Self {
+        Self::sign_at(secret, name, http_port, options, unix_time())
+    }
+
+    /// Same as `sign`, but as if signed at `timestamp` seconds since the Unix
+    /// epoch
+    pub fn sign_at(
+        secret: &JoinSecret,
+        name: &ValidName,
+        http_port: u16,
+        options: &JoinOptions,
+        timestamp: u64,
+    ) -> Self {
+        let mut nonce = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("The system random number generator works");
+
+        let nonce = u64::from_be_bytes(nonce);
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &secret.0);
+        let message =
+            signed_message(name, http_port, options, timestamp, nonce);
+        let mac = hmac::sign(&key, &message).as_ref().to_vec();
+
+        Self {
+            timestamp,
+            nonce,
+            mac,
+        }
+    }
+
+    /// Whether the signature was made with `secret` for a join as `name`
+    /// serving HTTP on `http_port` with `options`. The timestamp and nonce are
+    /// not checked
+    pub fn verify(
+        &self,
+        secret: &JoinSecret,
+        name: &ValidName,
+        http_port: u16,
+        options: &JoinOptions,
+    ) -> bool {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &secret.0);
+        let message = signed_message(
+            name,
+            http_port,
+            options,
+            self.timestamp,
+            self.nonce,
+        );
+        hmac::verify(&key, &message, &self.mac).is_ok()
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn signed_message(
+    name: &ValidName,
+    http_port: u16,
+    options: &JoinOptions,
+    timestamp: u64,
+    nonce: u64,
+) -> Vec<u8> {
+    // the options are signed as sent, less the signature itself
+    let options = JoinOptions {
+        auth: None,
+        ..options.clone()
+    };
+
+    let mut message = b"slot join\0".to_vec();
+    message.extend(name.to_string().as_bytes());
+    message.push(0);
+    message.extend(http_port.to_be_bytes());
+    message.extend(timestamp.to_be_bytes());
+    message.extend(nonce.to_be_bytes());
+    message.extend(options.to_bytes());
+    message
+}
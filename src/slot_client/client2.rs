@@ -1,38 +1,456 @@
+//! A Slot module client speaking protocol version 2
+
+use std::{
+    fmt::Display,
+    marker::PhantomData,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
 
-use serde::{Serialize, de::DeserializeOwned};
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use tokio_serde::{formats::MessagePack, Framed as SerdeFramed};
 use futures::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+};
+use tokio_serde::Framed as SerdeFramed;
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Framed, LengthDelimitedCodec},
+};
+
+use crate::{
+    client_impl::ClientHandle,
+    protocol::{JoinOptions, ValidName},
+    protocol2::{self, ClientMsg, RejectReason, ServerMsg},
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A connection carrying protocol2 messages. The message types are swapped
+/// between the client and server ends
+pub struct SlotConnection<Transport, MsgTypeSend, MsgTypeRecv> {
+    conn: SerdeFramed<
+        Framed<Transport, LengthDelimitedCodec>,
+        MsgTypeRecv,
+        MsgTypeSend,
+        MessagePack<MsgTypeRecv, MsgTypeSend>,
+    >,
+}
+
+/// MessagePack with exactly one message per frame. Unlike tokio-serde's, a
+/// frame with bytes left over after the message is refused rather than the
+/// rest ignored
+struct MessagePack<Recv, Send> {
+    types: PhantomData<fn() -> (Recv, Send)>,
+}
 
-pub struct SlotClient<Transport, MsgTypeSend, MsgTypeRecv> {
-    conn: SerdeFramed<Framed<Transport, LengthDelimitedCodec>,
-                      MsgTypeRecv, MsgTypeSend, MessagePack<MsgTypeRecv, MsgTypeSend>>
+impl<Recv, Send> Default for MessagePack<Recv, Send> {
+    fn default() -> Self {
+        Self { types: PhantomData }
+    }
 }
 
-impl<Transport, MsgTypeSend, MsgTypeRecv> SlotClient<Transport, MsgTypeSend, MsgTypeRecv>
+impl<Recv, Send> tokio_serde::Deserializer<Recv> for MessagePack<Recv, Send>
 where
-    Transport: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    Recv: DeserializeOwned,
+{
+    type Error = std::io::Error;
+
+    fn deserialize(
+        self: Pin<&mut Self>,
+        src: &BytesMut,
+    ) -> Result<Recv, Self::Error> {
+        let invalid =
+            |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+
+        let mut de = rmp_serde::Deserializer::new(&src[..]);
+        let msg =
+            Recv::deserialize(&mut de).map_err(|e| invalid(e.to_string()))?;
+
+        let rest = de.get_ref().len();
+        if rest != 0 {
+            return Err(invalid(format!("{rest} bytes after the message")));
+        }
+
+        Ok(msg)
+    }
+}
+
+impl<Recv, Send> tokio_serde::Serializer<Send> for MessagePack<Recv, Send>
+where
+    Send: Serialize,
+{
+    type Error = std::io::Error;
+
+    fn serialize(
+        self: Pin<&mut Self>,
+        item: &Send,
+    ) -> Result<Bytes, Self::Error> {
+        rmp_serde::to_vec(item).map(Bytes::from).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+        })
+    }
+}
+
+/// The module's end of a connection
+pub type ClientConnection<Transport> =
+    SlotConnection<Transport, ClientMsg, ServerMsg>;
+
+/// The Slot server's end of a connection
+pub type ServerConnection<Transport> =
+    SlotConnection<Transport, ServerMsg, ClientMsg>;
+
+impl<Transport, MsgTypeSend, MsgTypeRecv>
+    SlotConnection<Transport, MsgTypeSend, MsgTypeRecv>
+where
+    Transport: AsyncRead + AsyncWrite + Unpin,
     MsgTypeSend: Serialize + Unpin,
     MsgTypeRecv: DeserializeOwned + Unpin,
 {
     /// Wrap the generic transport
     pub fn new(trans: Transport) -> Self {
-        let trans_delimited = Framed::new(trans, LengthDelimitedCodec::new());
-        let trans_serialized = SerdeFramed::new(trans_delimited, MessagePack::default());
+        let codec = LengthDelimitedCodec::builder()
+            .max_frame_length(protocol2::MSG_LEN_MAX)
+            .new_codec();
+        let trans_delimited = Framed::new(trans, codec);
+        let trans_serialized =
+            SerdeFramed::new(trans_delimited, MessagePack::default());
         Self {
-            conn: trans_serialized
+            conn: trans_serialized,
         }
     }
 
-    /// Manually send a slot protocol message to the server
-    pub async fn send_msg(&mut self, msg: MsgTypeSend) -> Result<(), std::io::Error> {
+    /// Manually send a slot protocol message to the other end
+    pub async fn send_msg(
+        &mut self,
+        msg: MsgTypeSend,
+    ) -> Result<(), std::io::Error> {
         self.conn.send(msg).await
     }
 
-    /// Loops forever 
-    pub async fn heartbeat_task(mut self, msg: MsgTypeSend) -> std::io::Error {
-        for msg in self.conn
-        self.conn.split();
-        self.conn.send(msg).await
+    /// Wait for the next message. `None` once the other end closed the
+    /// connection
+    pub async fn recv_msg(
+        &mut self,
+    ) -> Option<Result<MsgTypeRecv, std::io::Error>> {
+        self.conn.next().await
     }
-}
\ No newline at end of file
+}
+
+/// Why a client lost its connection or failed to join
+#[derive(Debug)]
+pub enum ClientError {
+    /// The connection failed, or a message couldn't be encoded or decoded
+    Io(std::io::Error),
+
+    /// The server closed the connection
+    Closed,
+
+    /// No heartbeat arrived in time
+    TimedOut,
+
+    /// The server speaks none of our versions. Holds the ones it does speak
+    Unsupported(Vec<u16>),
+
+    /// The server refused to register the module
+    Rejected(RejectReason),
+
+    /// The server sent a message which makes no sense at this point
+    Unexpected(ServerMsg),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Closed => write!(f, "the server closed the connection"),
+            Self::TimedOut => write!(f, "no heartbeat from the server"),
+            Self::Unsupported(versions) => {
+                write!(f, "the server only speaks versions {versions:?}")
+            }
+            Self::Rejected(reason) => write!(f, "join rejected: {reason}"),
+            Self::Unexpected(msg) => write!(f, "unexpected message {msg:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// A module's connection to the Slot server, past the version handshake
+pub struct SlotClient<Transport> {
+    conn: ClientConnection<Transport>,
+    version: u16,
+}
+
+impl SlotClient<TcpStream> {
+    /// Connect to the Slot server listening on `server_port` on localhost
+    pub async fn connect(server_port: u16) -> Result<Self, ClientError> {
+        let stream =
+            TcpStream::connect((Ipv4Addr::LOCALHOST, server_port)).await?;
+        Self::handshake(stream).await
+    }
+}
+
+impl SlotClient<UnixStream> {
+    /// Connect to the Slot server listening on the Unix socket at `path`
+    pub async fn connect_unix(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, ClientError> {
+        let stream = UnixStream::connect(path).await?;
+        Self::handshake(stream).await
+    }
+}
+
+impl<Transport> SlotClient<Transport>
+where
+    Transport: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Agree with the server on the protocol version to speak over `trans`
+    pub async fn handshake(trans: Transport) -> Result<Self, ClientError> {
+        let mut conn = ClientConnection::new(trans);
+
+        conn.send_msg(ClientMsg::Hello {
+            versions: protocol2::SUPPORTED_VERSIONS.to_vec(),
+        })
+        .await?;
+
+        let mut client = Self { conn, version: 0 };
+
+        match client.recv().await? {
+            ServerMsg::Welcome { version }
+                if protocol2::SUPPORTED_VERSIONS.contains(&version) =>
+            {
+                client.version = version;
+                Ok(client)
+            }
+            ServerMsg::Unsupported { versions } => {
+                Err(ClientError::Unsupported(versions))
+            }
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// The protocol version agreed on with the server
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Register the module serving HTTP on `http_port` as `name`. The join is
+    /// signed if `options` has a secret
+    pub async fn join(
+        &mut self,
+        name: ValidName,
+        http_port: u16,
+        options: JoinOptions,
+    ) -> Result<(), ClientError> {
+        let options = options.signed(&name, http_port);
+
+        self.conn
+            .send_msg(ClientMsg::Join {
+                name,
+                http_port,
+                options,
+            })
+            .await?;
+
+        match self.recv().await? {
+            ServerMsg::ConfirmJoin => Ok(()),
+            ServerMsg::RejectJoin { reason } => {
+                Err(ClientError::Rejected(reason))
+            }
+            msg => Err(ClientError::Unexpected(msg)),
+        }
+    }
+
+    /// Answer the server's heartbeats until the connection is lost. Returns
+    /// why it was
+    pub async fn heartbeat_task(mut self) -> ClientError {
+        loop {
+            let msg =
+                tokio::time::timeout(protocol2::HEARTBEAT_TIMEOUT, self.recv())
+                    .await;
+
+            match msg {
+                Ok(Ok(ServerMsg::Heartbeat)) => {
+                    log::debug!("Received heartbeat from Slot server");
+                    let reply = self.conn.send_msg(ClientMsg::ReplyHeartbeat);
+                    if let Err(e) = reply.await {
+                        return e.into();
+                    }
+                }
+                Ok(Ok(msg)) => return ClientError::Unexpected(msg),
+                Ok(Err(e)) => return e,
+                Err(_) => return ClientError::TimedOut,
+            }
+        }
+    }
+
+    async fn recv(&mut self) -> Result<ServerMsg, ClientError> {
+        match self.conn.recv_msg().await {
+            Some(msg) => Ok(msg?),
+            None => Err(ClientError::Closed),
+        }
+    }
+}
+
+/// Spawns a task which keeps the module registered with the Slot server on
+/// `server_port`, reconnecting whenever the connection is lost. Must be called
+/// from within a tokio runtime. The module leaves the server when the handle
+/// shuts the task down
+///
+/// # Errors
+/// All error handling is encapsulated.
+pub fn run_client(
+    server_port: u16,
+    my_name: ValidName,
+    my_http_port: u16,
+    options: JoinOptions,
+) -> ClientHandle {
+    ClientHandle::from_task(tokio::spawn(keep_joined(
+        move || SlotClient::connect(server_port),
+        my_name,
+        my_http_port,
+        options,
+    )))
+}
+
+/// Like `run_client`, but connects to the Slot server's Unix socket at
+/// `server_socket`. Modules serving HTTP on a Unix socket set
+/// `options.http_socket` and may pass 0 as `my_http_port`
+///
+/// # Errors
+/// All error handling is encapsulated.
+pub fn run_client_unix(
+    server_socket: impl Into<PathBuf>,
+    my_name: ValidName,
+    my_http_port: u16,
+    options: JoinOptions,
+) -> ClientHandle {
+    let server_socket = server_socket.into();
+    ClientHandle::from_task(tokio::spawn(keep_joined(
+        move || SlotClient::connect_unix(server_socket.clone()),
+        my_name,
+        my_http_port,
+        options,
+    )))
+}
+
+/// Connect with `connect` and join, again and again for as long as the task
+/// runs
+async fn keep_joined<Transport, Connect, Fut>(
+    connect: Connect,
+    my_name: ValidName,
+    my_http_port: u16,
+    options: JoinOptions,
+) where
+    Transport: AsyncRead + AsyncWrite + Unpin,
+    Connect: Fn() -> Fut,
+    Fut: Future<Output = Result<SlotClient<Transport>, ClientError>>,
+{
+    log::info!("Starting Slot client");
+
+    loop {
+        let joined = async {
+            let mut client = connect().await?;
+            client
+                .join(my_name.clone(), my_http_port, options.clone())
+                .await?;
+            Ok::<_, ClientError>(client)
+        };
+
+        let err = match joined.await {
+            Ok(client) => {
+                log::info!("Received join confirmation from Slot server");
+                client.heartbeat_task().await
+            }
+            Err(e) => e,
+        };
+
+        log::warn!("Not connected to the Slot server. Retrying: \"{err}\"");
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Accept a module on `listener` and let it join
+    async fn accept_join(
+        listener: &TcpListener,
+    ) -> ServerConnection<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = ServerConnection::new(stream);
+
+        let Some(Ok(ClientMsg::Hello { .. })) = conn.recv_msg().await else {
+            panic!("expected Hello");
+        };
+        conn.send_msg(ServerMsg::Welcome { version: 2 })
+            .await
+            .unwrap();
+
+        let Some(Ok(ClientMsg::Join { .. })) = conn.recv_msg().await else {
+            panic!("expected Join");
+        };
+        conn.send_msg(ServerMsg::ConfirmJoin).await.unwrap();
+
+        conn
+    }
+
+    /// The module may be gone before it read the confirmation, in which case
+    /// the connection is reset rather than closed
+    async fn assert_closed(conn: &mut ServerConnection<TcpStream>) {
+        let msg = conn.recv_msg().await;
+        assert!(!matches!(msg, Some(Ok(_))), "unexpected {msg:?}");
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_closes_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = run_client(
+            port,
+            "blog".parse().unwrap(),
+            8080,
+            JoinOptions::default(),
+        );
+        let mut conn = accept_join(&listener).await;
+
+        handle.graceful_shutdown(async {}).await;
+
+        assert_closed(&mut conn).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = run_client(
+            port,
+            "blog".parse().unwrap(),
+            8080,
+            JoinOptions::default(),
+        );
+        let mut conn = accept_join(&listener).await;
+
+        handle.shutdown();
+
+        assert_closed(&mut conn).await;
+        let reconnect =
+            tokio::time::timeout(RECONNECT_DELAY * 2, listener.accept()).await;
+        assert!(reconnect.is_err());
+    }
+}
@@ -1,8 +1,13 @@
 //! A provided implementation of a Slot module client
 
 use std::{
+    future::Future,
     net::{SocketAddr, UdpSocket},
-    thread::sleep,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{sleep, JoinHandle},
     time::Duration,
 };
 
@@ -12,6 +17,112 @@ const SERVER_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
 const SERVER_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
 const SPAM_DELAY: Duration = Duration::from_secs(1);
 
+/// Stops a Slot client started by `run_client`, or by `client2::run_client`.
+/// Dropping it leaves the client running
+pub struct ClientHandle(Client);
+
+enum Client {
+    /// A client of the original protocol, running on its own thread
+    Thread {
+        stop: Arc<AtomicBool>,
+        /// The socket the client currently talks to the server from
+        socket: Arc<Mutex<Option<UdpSocket>>>,
+        server_addr: SocketAddr,
+        name: crate::protocol::ValidName,
+        thread: Option<JoinHandle<()>>,
+    },
+
+    /// A protocol 2 client, which leaves the server by closing its connection
+    Task(tokio::task::JoinHandle<()>),
+}
+
+impl ClientHandle {
+    pub(crate) fn from_task(task: tokio::task::JoinHandle<()>) -> Self {
+        Self(Client::Task(task))
+    }
+
+    /// Tell the Slot server the module is leaving, so it stops routing
+    /// requests to it right away, and stop the client. Blocks until a client
+    /// thread has stopped. A client task is only cancelled, and closes its
+    /// connection when the runtime next gets to it
+    pub fn shutdown(self) {
+        match self.0 {
+            Client::Thread {
+                stop,
+                socket,
+                server_addr,
+                name,
+                thread,
+            } => {
+                stop.store(true, Ordering::SeqCst);
+
+                if let Some(socket) = socket.lock().unwrap().take() {
+                    leave(&socket, server_addr, name);
+                }
+
+                if let Some(thread) = thread {
+                    thread.join().ok();
+                }
+            }
+            Client::Task(task) => task.abort(),
+        }
+    }
+
+    /// Waits for `signal`, then shuts the client down. Meant for axum's
+    /// `with_graceful_shutdown`, so that the module leaves the Slot server
+    /// before it finishes the requests in flight
+    ///
+    /// ```ignore
+    /// axum::serve(listener, routes)
+    ///     .with_graceful_shutdown(slot_handle.graceful_shutdown(async {
+    ///         tokio::signal::ctrl_c().await.ok();
+    ///     }))
+    /// ```
+    pub async fn graceful_shutdown(
+        self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) {
+        signal.await;
+        match self.0 {
+            Client::Task(task) => {
+                task.abort();
+                // the connection is closed once the task is dropped
+                task.await.ok();
+                log::info!("Left the Slot server");
+            }
+            thread => {
+                let handle = Self(thread);
+                tokio::task::spawn_blocking(move || handle.shutdown())
+                    .await
+                    .ok();
+            }
+        }
+    }
+}
+
+/// Send Bye for the module `name` to the Slot server at `server_addr` from
+/// `socket`, and wake the client thread if it is waiting on the socket
+fn leave(
+    socket: &UdpSocket,
+    server_addr: SocketAddr,
+    name: crate::protocol::ValidName,
+) {
+    let bye = crate::protocol::SlotMsg {
+        name,
+        ..crate::protocol::SlotMsg::new(crate::protocol::MsgIds::Bye)
+    }
+    .as_bytes();
+
+    match socket.send_to(&bye, server_addr) {
+        Ok(_) => log::info!("Left the Slot server"),
+        Err(e) => log::warn!("Unable to send Bye on socket: \"{e}\""),
+    }
+
+    if let Ok(addr) = socket.local_addr() {
+        socket.send_to(&[], addr).ok();
+    }
+}
+
 /// Spawns a thread to handle communication with the slot server
 ///
 /// # Errors
@@ -20,15 +131,63 @@ pub fn run_client(
     server_port: u16,
     my_name: crate::protocol::ValidName,
     my_http_port: u16,
-) {
+) -> ClientHandle {
+    run_client_with_options(
+        server_port,
+        my_name,
+        my_http_port,
+        crate::protocol::JoinOptions::default(),
+    )
+}
+
+/// Same as `run_client`, but also asks the Slot server to apply `options` to
+/// this module
+///
+/// # Errors
+/// All error handling is encapsulated.
+pub fn run_client_with_options(
+    server_port: u16,
+    my_name: crate::protocol::ValidName,
+    my_http_port: u16,
+    options: crate::protocol::JoinOptions,
+) -> ClientHandle {
     let server_addr =
         SocketAddr::new(std::net::Ipv4Addr::LOCALHOST.into(), server_port);
 
-    let _handle = std::thread::spawn(move || {
+    let stop = Arc::new(AtomicBool::new(false));
+    let socket = Arc::new(Mutex::new(None));
+    let name = my_name.clone();
+    let handle = |thread| {
+        ClientHandle(Client::Thread {
+            stop: stop.clone(),
+            socket: socket.clone(),
+            server_addr,
+            name,
+            thread,
+        })
+    };
+
+    let options_len = options.signed(&my_name, my_http_port).to_bytes().len();
+    if crate::protocol::PKT_LEN + options_len > crate::protocol::MAX_PKT_LEN {
+        log::error!(
+            "Join options are too large to send. Slot client will not start"
+        );
+        return handle(None);
+    }
+
+    let thread_stop = stop.clone();
+    let current_socket = socket.clone();
+
+    let thread = std::thread::spawn(move || {
+        let stop = thread_stop;
         log::info!("Starting Slot client");
         let mut fail_count = 0u8;
         // Restart loop
         loop {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+
             let my_slot_addr =
                 SocketAddr::new(std::net::Ipv4Addr::LOCALHOST.into(), 0);
             // Create socket
@@ -47,27 +206,20 @@ pub fn run_client(
                 socket.local_addr().expect("Address is bound at this point")
             );
 
-            let (len, my_name) = my_name.get();
+            *current_socket.lock().unwrap() = socket.try_clone().ok();
 
             // Construct static messages
-            let reg_msg = crate::protocol::SlotMsg {
-                cmd: crate::protocol::MsgIds::Join as u8,
-                module_http_port: my_http_port,
-                name_len: len,
-                name: my_name,
-            }
-            .as_bytes();
-
-            let hb_msg = crate::protocol::SlotMsg {
-                cmd: crate::protocol::MsgIds::Heartbeat as u8,
-                module_http_port: 0,
-                name_len: 0,
-                name: [0; _],
-            }
+            let hb_msg = crate::protocol::SlotMsg::new(
+                crate::protocol::MsgIds::Heartbeat,
+            )
             .as_bytes();
 
             // Retry loop
             loop {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+
                 // Check fail count
                 if fail_count >= SOCK_FAIL_BEFORE_RESTART {
                     fail_count = 0;
@@ -79,6 +231,19 @@ pub fn run_client(
 
                 log::debug!("Sending join request to {server_addr}");
 
+                // a signed join is only accepted once, so every attempt is
+                // signed anew
+                let mut reg_msg = crate::protocol::SlotMsg {
+                    cmd: crate::protocol::MsgIds::Join,
+                    module_http_port: my_http_port,
+                    name: my_name.clone(),
+                }
+                .as_bytes()
+                .to_vec();
+
+                let signed = options.signed(&my_name, my_http_port);
+                reg_msg.extend(signed.to_bytes());
+
                 // Request join
                 if let Err(e) = socket.send_to(&reg_msg, server_addr) {
                     log::error!(
@@ -96,12 +261,29 @@ pub fn run_client(
                     .set_read_timeout(Some(SERVER_RESPONSE_TIMEOUT))
                     .expect("The constant timeout is not zero");
 
-                match socket.recv_from(&mut buf) {
-                    Ok(_) => {
-                        let msg = crate::protocol::SlotMsg::from_bytes(buf);
+                let received = socket.recv_from(&mut buf);
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
 
-                        if msg.cmd == crate::protocol::MsgIds::ConfrimJoin as u8
-                        {
+                match received {
+                    Ok((len, _)) => {
+                        let msg = match crate::protocol::SlotMsg::from_bytes(
+                            &buf[..len],
+                        ) {
+                            Ok(msg) => msg,
+                            Err(e) => {
+                                log::warn!(
+                                    "Invalid response to join request from \
+                                     Slot server: {e}"
+                                );
+
+                                sleep(SPAM_DELAY);
+                                continue;
+                            }
+                        };
+
+                        if msg.cmd == crate::protocol::MsgIds::ConfrimJoin {
                             log::info!(
                                 "Received join confirmation from Slot server"
                             );
@@ -109,7 +291,8 @@ pub fn run_client(
                             log::warn!(
                                 "Slot server rejected join request. Make sure \
                                  to call run_client with the real port your \
-                                 HTTP listener is bound to."
+                                 HTTP listener is bound to, and the module's \
+                                 secret if the server has one."
                             );
                         }
                     }
@@ -145,7 +328,12 @@ pub fn run_client(
 
                 // Heartbeat loop
                 loop {
-                    match socket.recv_from(&mut buf) {
+                    let received = socket.recv_from(&mut buf);
+                    if stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+
+                    match received {
                         Ok(_) => {
                             log::debug!("Received heartbeat from Slot server");
                         }
@@ -187,4 +375,6 @@ pub fn run_client(
             sleep(SPAM_DELAY);
         }
     });
+
+    handle(Some(thread))
 }
@@ -0,0 +1,231 @@
+//! Information the Slot server adds to requests it forwards to modules
+//!
+//! The Slot server removes any of these headers sent by the client before
+//! adding its own, so they can be trusted as long as the module is only
+//! reachable through the Slot server (i.e., it listens on localhost).
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+
+/// The address of the client that made the request
+pub const REAL_IP: &str = "x-real-ip";
+
+/// Every proxy the request passed through, starting with the client
+pub const FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The scheme the client used to reach the Slot server
+pub const FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// The host the client asked for
+pub const FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// The path prefix the client used to reach the module, e.g., "/mymodule".
+/// Useful for building absolute links when the module's path is rewritten
+pub const FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// The RFC 7239 header carrying the address, scheme and host
+pub const FORWARDED: &str = "forwarded";
+
+/// The subject of the certificate the client authenticated with, e.g.,
+/// "CN=laptop, O=Example". Only sent to modules which use client certificates
+pub const CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+
+/// All headers set by the Slot server when forwarding a request
+pub const ALL: [&str; 7] = [
+    CLIENT_CERT_SUBJECT,
+    FORWARDED,
+    FORWARDED_FOR,
+    FORWARDED_HOST,
+    FORWARDED_PREFIX,
+    FORWARDED_PROTO,
+    REAL_IP,
+];
+
+/// Read the client's address from the headers of a forwarded request. The
+/// `Forwarded` header is preferred, then `X-Forwarded-For` and `X-Real-IP`
+pub fn client_addr(headers: &HeaderMap) -> Option<IpAddr> {
+    let header = |name| headers.get(name)?.to_str().ok();
+
+    let forwarded_for = || {
+        // the first element was added by the proxy nearest the client
+        let element = header(FORWARDED)?.split(',').next()?;
+        element.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("for").then(|| parse_node(value))?
+        })
+    };
+    let x_forwarded_for =
+        || parse_node(header(FORWARDED_FOR)?.split(',').next()?);
+    let real_ip = || parse_node(header(REAL_IP)?);
+
+    forwarded_for().or_else(x_forwarded_for).or_else(real_ip)
+}
+
+/// The address of a node as written in forwarding headers: an IP address,
+/// optionally with a port, and with IPv6 addresses in brackets if quoted,
+/// e.g., "192.0.2.1", "192.0.2.1:4711" or "\"[2001:db8::1]:4711\""
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    let node = node
+        .strip_prefix('"')
+        .and_then(|n| n.strip_suffix('"'))
+        .unwrap_or(node);
+
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    let ipv6 = node.strip_prefix('[')?.strip_suffix(']')?;
+    ipv6.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+}
+
+/// Extractor for the address of the client that made a forwarded request.
+/// Falls back to the address of the connection, if the module's server
+/// records it with [`ConnectInfo`]
+///
+/// ```rust,ignore
+/// async fn index(ClientAddr(addr): ClientAddr) -> String {
+///     format!("Hello {addr}")
+/// }
+/// ```
+///
+/// # Errors
+/// Rejects the request if it did not come through the Slot server, and the
+/// connection's address is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddr {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let connect_info = || {
+            let ConnectInfo(addr) =
+                parts.extensions.get::<ConnectInfo<SocketAddr>>()?;
+            Some(addr.ip())
+        };
+
+        client_addr(&parts.headers)
+            .or_else(connect_info)
+            .map(ClientAddr)
+            .ok_or((
+                StatusCode::BAD_REQUEST,
+                "Request was not forwarded by the Slot server",
+            ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderValue, Request};
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (name.parse().unwrap(), HeaderValue::from_static(value))
+            })
+            .collect()
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    /// What the extractor makes of a request with `headers`, which arrived
+    /// from `peer` if given
+    async fn extract(
+        headers: &[(&'static str, &'static str)],
+        peer: Option<&str>,
+    ) -> Result<ClientAddr, (StatusCode, &'static str)> {
+        let mut req = Request::new(());
+        *req.headers_mut() = self::headers(headers);
+        if let Some(peer) = peer {
+            let addr: SocketAddr = peer.parse().unwrap();
+            req.extensions_mut().insert(ConnectInfo(addr));
+        }
+        let (mut parts, ()) = req.into_parts();
+        ClientAddr::from_request_parts(&mut parts, &()).await
+    }
+
+    #[test]
+    fn forwarded_for_is_read() {
+        let cases = [
+            ("for=192.0.2.1", "192.0.2.1"),
+            ("for=\"192.0.2.1:4711\"", "192.0.2.1"),
+            ("For=192.0.2.1;proto=https;host=\"a.example\"", "192.0.2.1"),
+            ("proto=https; for=192.0.2.1", "192.0.2.1"),
+            ("for=\"[::1]\"", "::1"),
+            ("for=\"[::1]:4711\"", "::1"),
+            ("for=\"[2001:db8::1]:80\", for=192.0.2.1", "2001:db8::1"),
+        ];
+
+        for (forwarded, expected) in cases {
+            let headers = headers(&[(FORWARDED, forwarded)]);
+            assert_eq!(client_addr(&headers), ip(expected), "{forwarded}");
+        }
+    }
+
+    #[test]
+    fn forwarding_headers_are_read_in_order() {
+        let all = [
+            (FORWARDED, "for=192.0.2.1"),
+            (FORWARDED_FOR, "192.0.2.2, 10.0.0.1"),
+            (REAL_IP, "192.0.2.3"),
+        ];
+
+        assert_eq!(client_addr(&headers(&all)), ip("192.0.2.1"));
+        assert_eq!(client_addr(&headers(&all[1..])), ip("192.0.2.2"));
+        assert_eq!(client_addr(&headers(&all[2..])), ip("192.0.2.3"));
+        assert_eq!(
+            client_addr(&headers(&[(FORWARDED_FOR, "2001:db8::2")])),
+            ip("2001:db8::2")
+        );
+
+        // an obfuscated identifier gives way to the other headers
+        let hidden = [(FORWARDED, "for=_hidden"), (REAL_IP, "192.0.2.3")];
+        assert_eq!(client_addr(&headers(&hidden)), ip("192.0.2.3"));
+    }
+
+    #[test]
+    fn garbage_is_not_an_address() {
+        for forwarded in ["for=unknown", "for=", "proto=https", "for=[::1"] {
+            let headers = headers(&[(FORWARDED, forwarded)]);
+            assert_eq!(client_addr(&headers), None, "{forwarded}");
+        }
+        assert_eq!(client_addr(&headers(&[(REAL_IP, "localhost")])), None);
+        assert_eq!(client_addr(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn extractor_prefers_headers_to_the_connection() {
+        let forwarded = [(REAL_IP, "192.0.2.3")];
+        let from_slot = Some("127.0.0.1:40000");
+
+        assert_eq!(
+            extract(&forwarded, from_slot).await.unwrap(),
+            ClientAddr(ip("192.0.2.3").unwrap())
+        );
+        assert_eq!(
+            extract(&[], from_slot).await.unwrap(),
+            ClientAddr(ip("127.0.0.1").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn extractor_rejects_requests_from_nowhere() {
+        let (status, _) = extract(&[], None).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
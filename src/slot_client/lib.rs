@@ -1,5 +1,9 @@
 #![feature(ascii_char, slice_as_array)]
 //! Slot client implementation
 
+pub mod auth;
+pub mod client2;
 pub mod client_impl;
+pub mod forwarded;
 pub mod protocol;
+pub mod protocol2;
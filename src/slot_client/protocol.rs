@@ -2,6 +2,11 @@
 
 use std::{fmt::Display, str::FromStr};
 
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{JoinAuth, JoinSecret};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsgIds {
     // Client specific
     Join,
@@ -15,36 +20,142 @@ pub enum MsgIds {
     Bye,
 }
 
+impl TryFrom<u8> for MsgIds {
+    type Error = DecodeError;
+
+    fn try_from(cmd: u8) -> Result<Self, Self::Error> {
+        [
+            Self::Join,
+            Self::ConfrimJoin,
+            Self::RejectJoin,
+            Self::Heartbeat,
+            Self::Bye,
+        ]
+        .into_iter()
+        .find(|id| *id as u8 == cmd)
+        .ok_or(DecodeError::UnknownCommand(cmd))
+    }
+}
+
 pub const MAX_MOD_NAME_LEN: usize = 20;
-pub const PKT_LEN: usize = size_of::<SlotMsg>();
+/// Length of an encoded `SlotMsg`: the command, the big endian HTTP port, the
+/// name length and the name padded with zeros
+pub const PKT_LEN: usize = 1 + 2 + 1 + MAX_MOD_NAME_LEN;
+/// Largest packet either side will send. Join messages may be followed by
+/// encoded `JoinOptions`, which must fit within this
+pub const MAX_PKT_LEN: usize = 512;
 
-#[derive(Clone)]
-#[repr(C, packed)]
+/// A message of the legacy UDP protocol. Only `Join` carries a port and name,
+/// which are left empty otherwise and ignored when decoding
+#[derive(Debug, Clone, PartialEq)]
 pub struct SlotMsg {
-    pub cmd: u8,
+    pub cmd: MsgIds,
     pub module_http_port: u16,
-    pub name_len: u8,
-    pub name: [u8; MAX_MOD_NAME_LEN],
+    pub name: ValidName,
+}
+
+/// Why bytes received couldn't be decoded as a `SlotMsg`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Fewer bytes than a packet holds
+    TooShort(usize),
+
+    UnknownCommand(u8),
+
+    /// The name length is larger than the name field
+    NameTooLong(u8),
+
+    /// The name is empty or has characters other than ASCII letters and
+    /// digits
+    InvalidName,
 }
 
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(len) => {
+                write!(f, "packet of {len} bytes is shorter than {PKT_LEN}")
+            }
+            Self::UnknownCommand(cmd) => write!(f, "unknown command {cmd}"),
+            Self::NameTooLong(len) => {
+                write!(f, "name length {len} is larger than {MAX_MOD_NAME_LEN}")
+            }
+            Self::InvalidName => {
+                write!(f, "name is empty or not alphanumeric")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 impl SlotMsg {
+    /// A message without a port or name
+    pub fn new(cmd: MsgIds) -> Self {
+        Self {
+            cmd,
+            module_http_port: 0,
+            name: ValidName::default(),
+        }
+    }
+
     pub fn as_bytes(&self) -> [u8; PKT_LEN] {
-        let mut byte_swapped = self.clone();
-        byte_swapped.module_http_port = byte_swapped.module_http_port.to_be();
+        let (name_len, name) = self.name.get();
 
-        unsafe { *(&byte_swapped as *const Self as *const [u8; PKT_LEN]) }
+        let mut bytes = [0u8; PKT_LEN];
+        bytes[0] = self.cmd as u8;
+        bytes[1..3].copy_from_slice(&self.module_http_port.to_be_bytes());
+        bytes[3] = name_len;
+        bytes[4..].copy_from_slice(&name);
+
+        bytes
     }
 
-    pub fn from_bytes(bytes: [u8; PKT_LEN]) -> Self {
-        let mut pkt = unsafe { (*(bytes.as_ptr() as *const Self)).clone() };
+    /// Decode the packet at the start of `bytes`. Anything after it, such as
+    /// join options, is left to the caller
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let Some(bytes) = bytes.first_chunk::<PKT_LEN>() else {
+            return Err(DecodeError::TooShort(bytes.len()));
+        };
+
+        let cmd = MsgIds::try_from(bytes[0])?;
+        let mut msg = Self::new(cmd);
 
-        pkt.module_http_port = u16::from_be(pkt.module_http_port);
+        // joins name the module, and so do byes from all but older clients
+        let name_len = bytes[3];
+        let named = match cmd {
+            MsgIds::Join => true,
+            MsgIds::Bye => name_len != 0,
+            _ => false,
+        };
+        if !named {
+            return Ok(msg);
+        }
+
+        let name = bytes[4..]
+            .get(..usize::from(name_len))
+            .ok_or(DecodeError::NameTooLong(name_len))?;
+
+        msg.name = std::str::from_utf8(name)
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or(DecodeError::InvalidName)?;
 
-        pkt
+        if cmd == MsgIds::Join {
+            msg.module_http_port = u16::from_be_bytes([bytes[1], bytes[2]]);
+        }
+
+        Ok(msg)
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A module name, which is sent as a string and checked when decoded. Names
+/// are never empty, except for the default which stands in for the name in
+/// messages which don't carry one
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
 pub struct ValidName(u8, [u8; MAX_MOD_NAME_LEN]);
 
 impl FromStr for ValidName {
@@ -54,6 +165,10 @@ impl FromStr for ValidName {
         let ascii = s.as_ascii().ok_or("Invalid characters in string")?;
         let len = ascii.len();
 
+        if len == 0 {
+            return Err("String is empty".to_owned());
+        }
+
         if len > MAX_MOD_NAME_LEN {
             return Err(format!(
                 "String is too long. Must be at most {MAX_MOD_NAME_LEN}"
@@ -75,6 +190,20 @@ impl FromStr for ValidName {
     }
 }
 
+impl TryFrom<String> for ValidName {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ValidName> for String {
+    fn from(name: ValidName) -> Self {
+        name.to_string()
+    }
+}
+
 impl Display for ValidName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Display::fmt(str::from_utf8(&self.1[..self.0 as usize]).unwrap(), f)
@@ -85,8 +214,268 @@ impl ValidName {
     pub fn get(&self) -> (u8, [u8; MAX_MOD_NAME_LEN]) {
         (self.0, self.1)
     }
+}
+
+/// Optional settings a module sends along with its Join message
+///
+/// These are appended to the Join packet as MessagePack, so servers which
+/// don't know about them simply ignore them. Fields missing from the encoded
+/// options take their default value.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct JoinOptions {
+    /// How the module name at the start of a request path is treated before
+    /// the request is forwarded to the module
+    pub path: PathPolicy,
+
+    /// What happens to requests for the bare module root "/{name}"
+    pub root: RootPolicy,
+
+    /// Hostnames whose requests all go to this module regardless of their
+    /// path, e.g., "blog.example.com". A leading "*." claims every subdomain,
+    /// e.g., "*.example.com". Exact names take precedence over wildcards and
+    /// longer wildcards over shorter ones
+    pub hosts: Vec<String>,
+
+    /// Absolute path of a Unix socket the module serves HTTP on, used instead
+    /// of its HTTP port. Only accepted from modules which joined over the Slot
+    /// server's Unix socket, and the socket must be owned by the same user
+    pub http_socket: Option<String>,
+
+    /// Signature proving the module holds its secret. Filled in by the clients
+    /// from `secret` on every join
+    pub auth: Option<JoinAuth>,
+
+    /// Secret shared with the server to sign joins with. Required when the
+    /// server has a secret for the module's name, and never sent
+    #[serde(skip)]
+    pub secret: Option<JoinSecret>,
+}
 
-    pub fn new(length: u8, buf: [u8; MAX_MOD_NAME_LEN]) -> Self {
-        Self(length, buf)
+/// How the "/{name}" prefix of request paths is treated when forwarding
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum PathPolicy {
+    /// Forward "/{name}/rest" unchanged
+    #[default]
+    Keep,
+
+    /// Forward "/{name}/rest" as "/rest"
+    Strip,
+
+    /// Forward "/{name}/rest" as "{base}/rest". The base must begin with "/"
+    Rewrite(String),
+}
+
+/// How requests for "/{name}", without a trailing slash, are handled
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum RootPolicy {
+    /// Redirect the client to "/{name}/"
+    #[default]
+    Redirect,
+
+    /// Forward the request without adding a slash
+    Forward,
+}
+
+impl JoinOptions {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self)
+            .expect("Join options are always serializable")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+    }
+
+    /// The options to send with a join as `name` serving HTTP on `http_port`,
+    /// freshly signed if there is a secret
+    pub fn signed(&self, name: &ValidName, http_port: u16) -> Self {
+        let mut options = self.clone();
+        if let Some(secret) = &self.secret {
+            options.auth = Some(JoinAuth::sign(secret, name, http_port, self));
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const IDS: [MsgIds; 5] = [
+        MsgIds::Join,
+        MsgIds::ConfrimJoin,
+        MsgIds::RejectJoin,
+        MsgIds::Heartbeat,
+        MsgIds::Bye,
+    ];
+
+    fn join_bytes(name_len: u8, name: &[u8]) -> [u8; PKT_LEN] {
+        let mut bytes = [0u8; PKT_LEN];
+        bytes[0] = MsgIds::Join as u8;
+        bytes[3] = name_len;
+        bytes[4..4 + name.len()].copy_from_slice(name);
+        bytes
+    }
+
+    #[test]
+    fn join_round_trips() {
+        let msg = SlotMsg {
+            cmd: MsgIds::Join,
+            module_http_port: 8080,
+            name: "blog".parse().unwrap(),
+        };
+        assert_eq!(SlotMsg::from_bytes(&msg.as_bytes()), Ok(msg));
+    }
+
+    #[test]
+    fn bye_round_trips_with_or_without_a_name() {
+        let named = SlotMsg {
+            name: "blog".parse().unwrap(),
+            ..SlotMsg::new(MsgIds::Bye)
+        };
+        assert_eq!(SlotMsg::from_bytes(&named.as_bytes()), Ok(named));
+
+        let unnamed = SlotMsg::new(MsgIds::Bye);
+        assert_eq!(SlotMsg::from_bytes(&unnamed.as_bytes()), Ok(unnamed));
+    }
+
+    #[test]
+    fn other_messages_round_trip() {
+        for cmd in IDS.into_iter().filter(|id| *id != MsgIds::Join) {
+            let msg = SlotMsg::new(cmd);
+            assert_eq!(SlotMsg::from_bytes(&msg.as_bytes()), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn trailing_options_are_left() {
+        let msg = SlotMsg {
+            cmd: MsgIds::Join,
+            module_http_port: 1,
+            name: "a".parse().unwrap(),
+        };
+        let mut bytes = msg.as_bytes().to_vec();
+        bytes.extend(JoinOptions::default().to_bytes());
+        assert_eq!(SlotMsg::from_bytes(&bytes), Ok(msg));
+    }
+
+    #[test]
+    fn short_packets_are_refused() {
+        let bytes = SlotMsg::new(MsgIds::Heartbeat).as_bytes();
+        assert_eq!(
+            SlotMsg::from_bytes(&bytes[..PKT_LEN - 1]),
+            Err(DecodeError::TooShort(PKT_LEN - 1))
+        );
+        assert_eq!(SlotMsg::from_bytes(&[]), Err(DecodeError::TooShort(0)));
+    }
+
+    #[test]
+    fn unknown_commands_are_refused() {
+        let mut bytes = SlotMsg::new(MsgIds::Heartbeat).as_bytes();
+        bytes[0] = 200;
+        assert_eq!(
+            SlotMsg::from_bytes(&bytes),
+            Err(DecodeError::UnknownCommand(200))
+        );
+    }
+
+    #[test]
+    fn bad_names_are_refused() {
+        let too_long = MAX_MOD_NAME_LEN as u8 + 1;
+        assert_eq!(
+            SlotMsg::from_bytes(&join_bytes(too_long, b"blog")),
+            Err(DecodeError::NameTooLong(too_long))
+        );
+        assert_eq!(
+            SlotMsg::from_bytes(&join_bytes(0, b"")),
+            Err(DecodeError::InvalidName)
+        );
+        assert_eq!(
+            SlotMsg::from_bytes(&join_bytes(4, b"bl/g")),
+            Err(DecodeError::InvalidName)
+        );
+        assert_eq!(
+            SlotMsg::from_bytes(&join_bytes(5, b"blog")),
+            Err(DecodeError::InvalidName)
+        );
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert!("blog".parse::<ValidName>().is_ok());
+        assert!("a".repeat(MAX_MOD_NAME_LEN).parse::<ValidName>().is_ok());
+        assert!("".parse::<ValidName>().is_err());
+        assert!("a"
+            .repeat(MAX_MOD_NAME_LEN + 1)
+            .parse::<ValidName>()
+            .is_err());
+        assert!("my-blog".parse::<ValidName>().is_err());
+        assert!("blög".parse::<ValidName>().is_err());
+    }
+
+    #[test]
+    fn empty_names_are_not_deserialized() {
+        let bytes = rmp_serde::to_vec("").unwrap();
+        assert!(rmp_serde::from_slice::<ValidName>(&bytes).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn random_bytes_never_panic(
+            bytes in prop::collection::vec(any::<u8>(), 0..2 * PKT_LEN),
+        ) {
+            if let Ok(msg) = SlotMsg::from_bytes(&bytes) {
+                // the name is displayed in logs
+                let _ = msg.name.to_string();
+            }
+        }
+
+        #[test]
+        fn random_packets_decode_to_what_they_hold(
+            bytes in prop::array::uniform24(any::<u8>()),
+        ) {
+            match SlotMsg::from_bytes(&bytes) {
+                Ok(msg) if msg.cmd == MsgIds::Join => {
+                    let encoded = msg.as_bytes();
+                    prop_assert_eq!(&encoded[..4], &bytes[..4]);
+                    let name = &bytes[4..4 + usize::from(bytes[3])];
+                    prop_assert_eq!(msg.name.to_string().into_bytes(), name);
+                }
+                Ok(msg) if msg.cmd == MsgIds::Bye => {
+                    prop_assert_eq!(msg.module_http_port, 0);
+                    let name = &bytes[4..4 + usize::from(bytes[3])];
+                    prop_assert_eq!(msg.name.to_string().into_bytes(), name);
+                }
+                Ok(msg) => {
+                    let cmd = msg.cmd;
+                    prop_assert_eq!(msg, SlotMsg::new(cmd));
+                }
+                Err(_) => {}
+            }
+        }
+
+        #[test]
+        fn messages_round_trip(
+            cmd in prop::sample::select(&IDS[..]),
+            module_http_port in any::<u16>(),
+            name in "[a-zA-Z0-9]{1,20}",
+        ) {
+            let msg = match cmd {
+                MsgIds::Join => SlotMsg {
+                    cmd,
+                    module_http_port,
+                    name: name.parse().unwrap(),
+                },
+                MsgIds::Bye => SlotMsg {
+                    name: name.parse().unwrap(),
+                    ..SlotMsg::new(cmd)
+                },
+                _ => SlotMsg::new(cmd),
+            };
+            prop_assert_eq!(SlotMsg::from_bytes(&msg.as_bytes()), Ok(msg));
+        }
     }
 }
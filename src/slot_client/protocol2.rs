@@ -1,21 +1,383 @@
+//! Slot protocol, version 2
+//!
+//! Modules hold a TCP connection to the Slot port on localhost for as long as
+//! they are registered. Each message is MessagePack, framed by a 4 byte big
+//! endian length. A connection starts with the client's `Hello`, which the
+//! server answers with the version both sides will speak, then the client
+//! joins. From then on the server sends heartbeats which the client answers.
+//! A module leaves by closing the connection.
+//!
+//! The same messages can be sent over a Unix socket, when the server listens on
+//! one, which lets file permissions and the peer's credentials decide who may
+//! register.
+//!
+//! Servers keep accepting the legacy `SlotMsg` packets over UDP on the same
+//! port, so modules can move to this protocol one at a time.
+
+use std::{fmt::Display, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
+use crate::protocol::{JoinOptions, ValidName};
+
+/// The versions of the protocol this library speaks, newest first. Version 1
+/// is the legacy UDP protocol, which has no handshake
+pub const SUPPORTED_VERSIONS: &[u16] = &[2];
+
+/// Largest message either side will send or accept
+pub const MSG_LEN_MAX: usize = 4096;
 
-pub const MODULE_NAME_LEN_MAX: usize = 32;
-pub const PACKET_SIZE_MAX: usize = 64;
+/// How often the server sends heartbeats
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
-#[derive(Serialize, Deserialize, Debug)]
+/// How long either side waits for a heartbeat, or its reply, before giving up
+/// on the connection
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long the server waits for `Hello` and `Join`
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ClientMsg {
+    /// The first message on a connection, listing the versions the client
+    /// speaks
+    Hello {
+        versions: Vec<u16>,
+    },
+
+    /// Ask to be registered under `name`, serving HTTP on `http_port` on
+    /// localhost
     Join {
-        name_len: u8,
-        name: String, // Use a different type which disallows non-url-safe chars
+        name: ValidName,
         http_port: u16,
+        options: JoinOptions,
     },
+
     ReplyHeartbeat,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ServerMsg {
-    ConfrimJoin,
+    /// The version chosen for the rest of the connection
+    Welcome {
+        version: u16,
+    },
+
+    /// None of the client's versions are supported. Lists the server's, after
+    /// which the server closes the connection
+    Unsupported {
+        versions: Vec<u16>,
+    },
+
+    ConfirmJoin,
+
+    /// The module was not registered, and the connection will be closed
+    RejectJoin {
+        reason: RejectReason,
+    },
+
     Heartbeat,
 }
+
+/// Why the server refused to register a module
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    /// Another module is registered under the name. Only a join signed with
+    /// the name's secret may replace it
+    NameTaken,
+
+    /// The server only registers modules it has a secret for
+    NotAllowed,
+
+    /// The server has a secret for the name, but the join wasn't signed
+    AuthRequired,
+
+    /// The join wasn't signed with the name's secret
+    BadSignature,
+
+    /// The join was signed too long ago, or the clocks disagree
+    Expired,
+
+    /// A join with the same signature was already received
+    Replayed,
+
+    /// Anything else, e.g., invalid options
+    Invalid(String),
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NameTaken => write!(f, "the name is taken"),
+            Self::NotAllowed => write!(f, "the name is not on the allow-list"),
+            Self::AuthRequired => write!(f, "the join must be signed"),
+            Self::BadSignature => write!(f, "the signature is invalid"),
+            Self::Expired => write!(f, "the signature has expired"),
+            Self::Replayed => write!(f, "the join was replayed"),
+            Self::Invalid(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// The newest version both sides speak, if any
+pub fn negotiate_version(ours: &[u16], theirs: &[u16]) -> Option<u16> {
+    ours.iter().copied().filter(|v| theirs.contains(v)).max()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        auth::{JoinAuth, JoinSecret},
+        client2::{ClientConnection, ServerConnection, SlotConnection},
+        protocol::{PathPolicy, RootPolicy},
+    };
+
+    fn connected() -> (
+        ClientConnection<tokio::io::DuplexStream>,
+        ServerConnection<tokio::io::DuplexStream>,
+    ) {
+        let (client, server) = tokio::io::duplex(MSG_LEN_MAX * 2);
+        (ClientConnection::new(client), ServerConnection::new(server))
+    }
+
+    fn options() -> JoinOptions {
+        JoinOptions {
+            path: PathPolicy::Rewrite("/base".to_owned()),
+            root: RootPolicy::Forward,
+            hosts: vec![
+                "blog.example.com".to_owned(),
+                "*.example.org".to_owned(),
+            ],
+            http_socket: Some("/run/blog/http.sock".to_owned()),
+            auth: Some(JoinAuth {
+                timestamp: 1_700_000_000,
+                nonce: u64::MAX,
+                mac: vec![0xab; 32],
+            }),
+            secret: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn client_messages_round_trip() {
+        let (mut client, mut server) = connected();
+
+        let msgs = [
+            ClientMsg::Hello {
+                versions: vec![3, 2, 1],
+            },
+            ClientMsg::Hello { versions: vec![] },
+            ClientMsg::Join {
+                name: "blog".parse().unwrap(),
+                http_port: 8080,
+                options: options(),
+            },
+            ClientMsg::Join {
+                name: "a".parse().unwrap(),
+                http_port: 0,
+                options: JoinOptions::default(),
+            },
+            ClientMsg::ReplyHeartbeat,
+        ];
+
+        for msg in msgs {
+            client.send_msg(msg.clone()).await.unwrap();
+            assert_eq!(server.recv_msg().await.unwrap().unwrap(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn server_messages_round_trip() {
+        let (mut client, mut server) = connected();
+
+        let reasons = [
+            RejectReason::NameTaken,
+            RejectReason::NotAllowed,
+            RejectReason::AuthRequired,
+            RejectReason::BadSignature,
+            RejectReason::Expired,
+            RejectReason::Replayed,
+            RejectReason::Invalid("bad host claim".to_owned()),
+        ];
+        let msgs = [
+            ServerMsg::Welcome { version: 2 },
+            ServerMsg::Unsupported {
+                versions: SUPPORTED_VERSIONS.to_vec(),
+            },
+            ServerMsg::ConfirmJoin,
+            ServerMsg::Heartbeat,
+        ]
+        .into_iter()
+        .chain(reasons.map(|reason| ServerMsg::RejectJoin { reason }));
+
+        for msg in msgs {
+            server.send_msg(msg.clone()).await.unwrap();
+            assert_eq!(client.recv_msg().await.unwrap().unwrap(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn secret_is_never_sent() {
+        let (mut client, mut server) = connected();
+
+        let msg = ClientMsg::Join {
+            name: "blog".parse().unwrap(),
+            http_port: 8080,
+            options: JoinOptions {
+                secret: Some(JoinSecret::from("hunter2")),
+                ..options()
+            },
+        };
+        client.send_msg(msg).await.unwrap();
+
+        let Some(Ok(ClientMsg::Join {
+            options: received, ..
+        })) = server.recv_msg().await
+        else {
+            panic!("expected a join");
+        };
+        assert_eq!(received, options());
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused() {
+        let (mut client, _server) = connected();
+
+        let msg = ClientMsg::Hello {
+            versions: vec![2; MSG_LEN_MAX],
+        };
+        assert!(client.send_msg(msg).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_connection_ends_the_stream() {
+        let (client, mut server) = connected();
+        drop(client);
+        assert!(server.recv_msg().await.is_none());
+    }
+
+    #[test]
+    fn newest_common_version_is_chosen() {
+        assert_eq!(negotiate_version(&[2], &[2]), Some(2));
+        assert_eq!(negotiate_version(&[3, 2], &[1, 2, 3]), Some(3));
+        assert_eq!(negotiate_version(&[2, 3], &[3, 2]), Some(3));
+        assert_eq!(negotiate_version(&[4, 2], &[3, 2, 1]), Some(2));
+    }
+
+    #[test]
+    fn no_common_version() {
+        assert_eq!(negotiate_version(&[2], &[1]), None);
+        assert_eq!(negotiate_version(&[2], &[]), None);
+        assert_eq!(negotiate_version(&[], &[2]), None);
+    }
+
+    /// Every message the server's end decodes from `bytes`
+    fn server_receives(bytes: Vec<u8>) -> Vec<std::io::Result<ClientMsg>> {
+        receive(ServerConnection::new(Cursor::new(bytes)))
+    }
+
+    /// Every message the module's end decodes from `bytes`
+    fn client_receives(bytes: Vec<u8>) -> Vec<std::io::Result<ServerMsg>> {
+        receive(ClientConnection::new(Cursor::new(bytes)))
+    }
+
+    fn receive<Send, Recv>(
+        mut conn: SlotConnection<Cursor<Vec<u8>>, Send, Recv>,
+    ) -> Vec<std::io::Result<Recv>>
+    where
+        Send: serde::Serialize + Unpin,
+        Recv: serde::de::DeserializeOwned + Unpin,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut msgs = Vec::new();
+            while let Some(msg) = conn.recv_msg().await {
+                let failed = msg.is_err();
+                msgs.push(msg);
+                if failed {
+                    break;
+                }
+            }
+            msgs
+        })
+    }
+
+    /// `payload` with the length prefix the connections expect
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_reading_them() {
+        let len = (MSG_LEN_MAX as u32 + 1).to_be_bytes();
+        let received = server_receives(len.to_vec());
+        assert!(matches!(received[..], [Err(_)]));
+
+        let len = u32::MAX.to_be_bytes();
+        let received = client_receives(len.to_vec());
+        assert!(matches!(received[..], [Err(_)]));
+    }
+
+    #[test]
+    fn huge_collections_in_small_frames_are_refused() {
+        // a map and an array each claiming 2^32 - 1 entries
+        let map = frame(&[0xDF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(matches!(server_receives(map.clone())[..], [Err(_)]));
+        assert!(matches!(client_receives(map)[..], [Err(_)]));
+
+        let mut hello = vec![0x81, 0xA5];
+        hello.extend(b"Hello");
+        hello.extend([0x81, 0xA8]);
+        hello.extend(b"versions");
+        hello.extend([0xDD, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(matches!(server_receives(frame(&hello))[..], [Err(_)]));
+    }
+
+    #[test]
+    fn bytes_after_a_message_are_refused() {
+        let mut payload =
+            rmp_serde::to_vec(&ServerMsg::Welcome { version: 2 }).unwrap();
+        assert!(matches!(
+            client_receives(frame(&payload))[..],
+            [Ok(ServerMsg::Welcome { version: 2 })]
+        ));
+
+        payload.push(0);
+        assert!(matches!(client_receives(frame(&payload))[..], [Err(_)]));
+    }
+
+    proptest! {
+        // anything shorter could be a unit variant by its index, e.g., 4 for
+        // `ServerMsg::Heartbeat`
+        #[test]
+        fn random_frames_are_refused(
+            payload in prop::collection::vec(any::<u8>(), 4..MSG_LEN_MAX),
+        ) {
+            let received = server_receives(frame(&payload));
+            prop_assert!(matches!(received[..], [Err(_)]), "{received:?}");
+
+            let received = client_receives(frame(&payload));
+            prop_assert!(matches!(received[..], [Err(_)]), "{received:?}");
+        }
+
+        #[test]
+        fn random_streams_are_refused(
+            bytes in prop::collection::vec(any::<u8>(), 1..2 * MSG_LEN_MAX),
+        ) {
+            let received = server_receives(bytes.clone());
+            prop_assert!(matches!(received[..], [Err(_)]), "{received:?}");
+
+            let received = client_receives(bytes);
+            prop_assert!(matches!(received[..], [Err(_)]), "{received:?}");
+        }
+    }
+}
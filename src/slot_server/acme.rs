@@ -0,0 +1,970 @@
+//! Certificates provisioned automatically from an ACME certificate authority
+//! such as Let's Encrypt (RFC 8555)
+//!
+//! The certificate and its key are kept in the storage directory together
+//! with the ACME account key. Until the first certificate is issued, a
+//! short-lived self-signed placeholder is served so the server can start.
+//! Certificates are renewed in the background some time before they expire
+//! and swapped into the TLS acceptor without a restart.
+//!
+//! Domains are validated with either HTTP-01, answered by the HTTP redirect
+//! server, or TLS-ALPN-01, answered by the HTTPS server itself.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rcgen::{
+    CertificateParams, CustomExtension, DistinguishedName, KeyPair,
+    PKCS_ECDSA_P256_SHA256,
+};
+use reqwest::header::{HeaderValue, ACCEPT, CONTENT_TYPE, LOCATION};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_rustls::rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    sign::CertifiedKey,
+};
+
+use crate::{
+    cli::{AcmeChallenge, Args},
+    config::CertPaths,
+    error::error_chain,
+    tls::CertResolver,
+};
+
+/// The ALPN protocol ACME servers use for TLS-ALPN-01 validation (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// How long before expiry a certificate is renewed. Short-lived certificates
+/// are renewed once a third of their lifetime remains instead
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How long to wait after a failed attempt to get a certificate
+const RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Longest single sleep between renewal checks. Keeps the schedule close to
+/// the wall clock even if the machine was suspended
+const MAX_SLEEP: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Delay between checks on a pending authorization or order
+const POLL_DELAY: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+/// Key authorizations for pending HTTP-01 challenges, keyed by token
+pub type Http01Tokens = Arc<RwLock<HashMap<String, String>>>;
+
+/// Routes answering HTTP-01 challenges with the key authorizations in
+/// `tokens`
+pub fn challenge_routes(tokens: Http01Tokens) -> Router {
+    let challenge =
+        async |State(tokens): State<Http01Tokens>,
+               UrlPath(token): UrlPath<String>| {
+            match tokens.read().unwrap().get(&token) {
+                Some(key_auth) => Ok(key_auth.clone()),
+                None => Err(StatusCode::NOT_FOUND),
+            }
+        };
+
+    Router::new()
+        .route("/.well-known/acme-challenge/{token}", get(challenge))
+        .with_state(tokens)
+}
+
+/// Where and how to get certificates
+#[derive(Debug, Clone)]
+pub struct AcmeSettings {
+    pub domains: Vec<String>,
+    pub directory: String,
+    pub email: Option<String>,
+    pub storage: PathBuf,
+    pub challenge: AcmeChallenge,
+    pub ca_cert: Option<String>,
+}
+
+impl AcmeSettings {
+    /// The ACME settings given on the command line, if any domains were given
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.acme_domains.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            domains: args.acme_domains.clone(),
+            directory: args.acme_directory.clone(),
+            email: args.acme_email.clone(),
+            storage: PathBuf::from(&args.acme_storage),
+            challenge: args.acme_challenge,
+            ca_cert: args.acme_ca_cert.clone(),
+        })
+    }
+
+    /// Where the issued certificate chain and its key are stored
+    pub fn cert_paths(&self) -> CertPaths {
+        let path = |file: &str| self.storage.join(file).display().to_string();
+        CertPaths {
+            cert: path("cert.pem"),
+            key: path("key.pem"),
+            ocsp: None,
+        }
+    }
+
+    fn account_key_path(&self) -> PathBuf {
+        self.storage.join("account.key")
+    }
+}
+
+/// Make sure there is a certificate to serve before the first one is issued,
+/// by creating a self-signed one if needed. A self-signed certificate is
+/// always due for renewal, so it is replaced as soon as the renewal task
+/// starts
+pub fn ensure_placeholder(settings: &AcmeSettings) -> Result<(), String> {
+    let paths = settings.cert_paths();
+    if Path::new(&paths.cert).exists() && Path::new(&paths.key).exists() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&settings.storage).map_err(|e| {
+        format!("unable to create \"{}\": {e}", settings.storage.display())
+    })?;
+
+    let key = new_key()?;
+    let mut params = CertificateParams::new(settings.domains.clone())
+        .map_err(|e| format!("invalid domain: {e}"))?;
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::MINUTE;
+    params.not_after = now + time::Duration::DAY;
+
+    let cert = params
+        .self_signed(&key)
+        .map_err(|e| format!("unable to create placeholder: {e}"))?;
+
+    write_cert_and_key(
+        &paths,
+        cert.pem().as_bytes(),
+        key.serialize_pem().as_bytes(),
+    )?;
+
+    log::info!(
+        "Serving a self-signed placeholder until a certificate is issued for \
+         {:?}",
+        settings.domains
+    );
+    Ok(())
+}
+
+/// Keep the certificate in `settings.storage` current, reloading `resolver`
+/// whenever a new one is issued
+pub fn spawn(
+    settings: AcmeSettings,
+    resolver: Arc<CertResolver>,
+    tokens: Http01Tokens,
+) {
+    tokio::spawn(async move {
+        let http = match build_http_client(&settings) {
+            Ok(h) => h,
+            Err(e) => {
+                log::error!("ACME disabled: \"{e}\"");
+                return;
+            }
+        };
+
+        loop {
+            let wait = renewal_wait(&settings);
+            if !wait.is_zero() {
+                log::debug!("Next ACME renewal check in {wait:?}");
+                tokio::time::sleep(wait.min(MAX_SLEEP)).await;
+                continue;
+            }
+
+            log::info!("Requesting certificate for {:?}", settings.domains);
+
+            let issued =
+                issue_certificate(&settings, &http, &resolver, &tokens).await;
+
+            match issued.and_then(|()| resolver.reload()) {
+                Ok(()) => log::info!(
+                    "Installed new certificate for {:?}",
+                    settings.domains
+                ),
+                Err(e) => {
+                    log::error!(
+                        "Failed to get certificate. Retrying in {RETRY_DELAY:?}: \
+                         \"{e}\""
+                    );
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    });
+}
+
+fn build_http_client(
+    settings: &AcmeSettings,
+) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!("slot/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30));
+
+    if let Some(path) = &settings.ca_cert {
+        let pem = std::fs::read(path)
+            .map_err(|e| format!("unable to read \"{path}\": {e}"))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| format!("invalid certificate in \"{path}\": {e}"))?;
+        builder = builder.add_root_certificate(cert);
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// How long until the stored certificate is due for renewal. Zero if it is
+/// due now, is missing, is the self-signed placeholder or doesn't cover every
+/// domain
+fn renewal_wait(settings: &AcmeSettings) -> Duration {
+    let paths = settings.cert_paths();
+
+    let Ok(pem) = std::fs::read(&paths.cert) else {
+        return Duration::ZERO;
+    };
+    let Ok((_, pem)) = x509_parser::pem::parse_x509_pem(&pem) else {
+        return Duration::ZERO;
+    };
+    let Ok(cert) = pem.parse_x509() else {
+        return Duration::ZERO;
+    };
+
+    // a certificate authority never issues self-signed certificates
+    if cert.issuer() == cert.subject() {
+        log::info!("Stored certificate is the self-signed placeholder");
+        return Duration::ZERO;
+    }
+
+    let names: Vec<&str> = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                x509_parser::extensions::GeneralName::DNSName(n) => Some(*n),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    if !settings.domains.iter().all(|d| names.contains(&d.as_str())) {
+        log::info!("Stored certificate doesn't cover every ACME domain");
+        return Duration::ZERO;
+    }
+
+    let validity = cert.validity();
+    let not_before = validity.not_before.timestamp();
+    let not_after = validity.not_after.timestamp();
+
+    let lifetime = u64::try_from(not_after - not_before).unwrap_or_default();
+    let renew_at = u64::try_from(not_after)
+        .unwrap_or_default()
+        .saturating_sub(RENEW_BEFORE.as_secs().min(lifetime / 3));
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    Duration::from_secs(renew_at.saturating_sub(now))
+}
+
+/// Run an ACME order for all domains and store the certificate it produces
+async fn issue_certificate(
+    settings: &AcmeSettings,
+    http: &reqwest::Client,
+    resolver: &CertResolver,
+    tokens: &Http01Tokens,
+) -> Result<(), String> {
+    let account_key = load_or_create_account_key(settings)?;
+    let mut acme = AcmeClient::new(http.clone(), account_key, settings).await?;
+
+    let identifiers: Vec<Value> = settings
+        .domains
+        .iter()
+        .map(|d| json!({ "type": "dns", "value": d }))
+        .collect();
+
+    let response = acme
+        .post(
+            &acme.directory.new_order.clone(),
+            Some(json!({ "identifiers": identifiers })),
+        )
+        .await?;
+    let order_url = location(&response)?;
+    let order: Order = json_body(response).await?;
+
+    // the challenge responses are withdrawn whether or not validation passed
+    let validated = acme
+        .authorize_all(&order.authorizations, settings, resolver, tokens)
+        .await;
+    tokens.write().unwrap().clear();
+    for domain in &settings.domains {
+        resolver.set_alpn_challenge(domain, None);
+    }
+    validated?;
+
+    let order = acme.poll_order(&order_url, &["ready", "valid"]).await?;
+
+    let cert_key = new_key()?;
+    let mut params = CertificateParams::new(settings.domains.clone())
+        .map_err(|e| format!("invalid domain: {e}"))?;
+    params.distinguished_name = DistinguishedName::new();
+    let csr = params
+        .serialize_request(&cert_key)
+        .map_err(|e| format!("unable to create CSR: {e}"))?;
+
+    if order.status == "ready" {
+        let csr = URL_SAFE_NO_PAD.encode(csr.der());
+        acme.post(&order.finalize, Some(json!({ "csr": csr })))
+            .await?;
+    }
+
+    let order = acme.poll_order(&order_url, &["valid"]).await?;
+    let Some(cert_url) = order.certificate else {
+        return Err("valid order has no certificate".to_owned());
+    };
+
+    let chain =
+        acme.post(&cert_url, None)
+            .await?
+            .text()
+            .await
+            .map_err(|e| {
+                format!("unable to download certificate: {}", error_chain(&e))
+            })?;
+
+    write_cert_and_key(
+        &settings.cert_paths(),
+        chain.as_bytes(),
+        cert_key.serialize_pem().as_bytes(),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+/// An ACME account session. Requests are signed with the account key and
+/// each carries a fresh nonce from the server
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetch the directory and register (or find) the account
+    async fn new(
+        http: reqwest::Client,
+        key: EcdsaKeyPair,
+        settings: &AcmeSettings,
+    ) -> Result<Self, String> {
+        let directory = http
+            .get(&settings.directory)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| {
+                format!("unable to fetch ACME directory: {}", error_chain(&e))
+            })?
+            .json()
+            .await
+            .map_err(|e| {
+                format!("invalid ACME directory: {}", error_chain(&e))
+            })?;
+
+        // the public key is an uncompressed point: 0x04 || x || y
+        let point = key.public_key().as_ref();
+        let (x, y) = point[1..].split_at(32);
+        let (x, y) = (URL_SAFE_NO_PAD.encode(x), URL_SAFE_NO_PAD.encode(y));
+
+        // RFC 7638 requires the members in lexicographic order
+        let canonical =
+            format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let thumbprint =
+            URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes()));
+
+        let mut client = Self {
+            http,
+            directory,
+            key,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &settings.email {
+            account["contact"] = json!([format!("mailto:{email}")]);
+        }
+
+        let new_account = client.directory.new_account.clone();
+        let response = client.post(&new_account, Some(account)).await?;
+        client.kid = Some(location(&response)?);
+
+        Ok(client)
+    }
+
+    /// Complete the challenge of every pending authorization
+    async fn authorize_all(
+        &mut self,
+        urls: &[String],
+        settings: &AcmeSettings,
+        resolver: &CertResolver,
+        tokens: &Http01Tokens,
+    ) -> Result<(), String> {
+        let kind = match settings.challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+
+        for url in urls {
+            let authz: Authorization =
+                json_body(self.post(url, None).await?).await?;
+            if authz.status == "valid" {
+                continue;
+            }
+
+            let domain = authz.identifier.value;
+            let Some(challenge) =
+                authz.challenges.into_iter().find(|c| c.kind == kind)
+            else {
+                return Err(format!(
+                    "no {kind} challenge offered for {domain}"
+                ));
+            };
+
+            let key_auth = format!("{}.{}", challenge.token, self.thumbprint);
+
+            match settings.challenge {
+                AcmeChallenge::Http01 => {
+                    tokens
+                        .write()
+                        .unwrap()
+                        .insert(challenge.token.clone(), key_auth);
+                }
+                AcmeChallenge::TlsAlpn01 => {
+                    let cert = alpn_challenge_cert(&domain, &key_auth)?;
+                    resolver.set_alpn_challenge(&domain, Some(cert));
+                }
+            }
+
+            log::debug!("Answering {kind} challenge for {domain}");
+            self.post(&challenge.url, Some(json!({}))).await?;
+
+            self.poll(url, |a: &Authorization| match a.status.as_str() {
+                "valid" => Some(Ok(())),
+                "pending" | "processing" => None,
+                status => {
+                    Some(Err(format!("authorization for {domain} is {status}")))
+                }
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait until the order reaches one of `wanted`
+    async fn poll_order(
+        &mut self,
+        url: &str,
+        wanted: &[&str],
+    ) -> Result<Order, String> {
+        self.poll(url, |o: &Order| {
+            if wanted.contains(&o.status.as_str()) {
+                return Some(Ok(()));
+            }
+            match o.status.as_str() {
+                "pending" | "ready" | "processing" => None,
+                status => Some(Err(format!("order is {status}"))),
+            }
+        })
+        .await
+    }
+
+    /// Fetch the resource at `url` until `done` gives a result
+    async fn poll<T: for<'de> Deserialize<'de>>(
+        &mut self,
+        url: &str,
+        done: impl Fn(&T) -> Option<Result<(), String>>,
+    ) -> Result<T, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: T = json_body(self.post(url, None).await?).await?;
+            if let Some(result) = done(&resource) {
+                return result.map(|()| resource);
+            }
+            tokio::time::sleep(POLL_DELAY).await;
+        }
+
+        Err(format!("gave up waiting on \"{url}\""))
+    }
+
+    /// Send a signed request. Without a payload this is a "POST-as-GET"
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<reqwest::Response, String> {
+        let payload = match payload {
+            Some(p) => URL_SAFE_NO_PAD.encode(p.to_string()),
+            None => String::new(),
+        };
+
+        // a stale nonce is retried once with the fresh one that came with
+        // the error
+        let mut retried = false;
+
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(n) => n,
+                None => self.new_nonce().await?,
+            };
+
+            let mut protected =
+                json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(
+                    &SystemRandom::new(),
+                    format!("{protected}.{payload}").as_bytes(),
+                )
+                .map_err(|_| "unable to sign ACME request".to_owned())?;
+
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature),
+            });
+
+            let response = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .header(ACCEPT, "application/pem-certificate-chain, */*")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|e| {
+                    format!("request to \"{url}\" failed: {}", error_chain(&e))
+                })?;
+
+            self.nonce = response
+                .headers()
+                .get("replay-nonce")
+                .and_then(|n| n.to_str().ok())
+                .map(str::to_owned);
+
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status = response.status();
+            let problem: Value = response.json().await.unwrap_or_default();
+            let kind = problem["type"].as_str().unwrap_or_default();
+
+            if kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+
+            return Err(format!(
+                "\"{url}\" answered {status}: {}",
+                problem["detail"].as_str().unwrap_or(kind)
+            ));
+        }
+    }
+
+    async fn new_nonce(&self) -> Result<String, String> {
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| {
+            format!("unable to get nonce: {}", error_chain(&e))
+        })?;
+
+        response
+            .headers()
+            .get("replay-nonce")
+            .and_then(|n| n.to_str().ok())
+            .map(str::to_owned)
+            .ok_or_else(|| "no nonce in newNonce response".to_owned())
+    }
+}
+
+fn location(response: &reqwest::Response) -> Result<String, String> {
+    response
+        .headers()
+        .get(LOCATION)
+        .map(HeaderValue::to_str)
+        .and_then(Result::ok)
+        .map(str::to_owned)
+        .ok_or_else(|| format!("no Location from \"{}\"", response.url()))
+}
+
+async fn json_body<T: for<'de> Deserialize<'de>>(
+    response: reqwest::Response,
+) -> Result<T, String> {
+    let url = response.url().to_string();
+    response.json().await.map_err(|e| {
+        format!("unexpected response from \"{url}\": {}", error_chain(&e))
+    })
+}
+
+/// The self-signed certificate presented for a TLS-ALPN-01 challenge, which
+/// carries the digest of the key authorization (RFC 8737)
+fn alpn_challenge_cert(
+    domain: &str,
+    key_auth: &str,
+) -> Result<CertifiedKey, String> {
+    let key = new_key()?;
+    let mut params = CertificateParams::new(vec![domain.to_owned()])
+        .map_err(|e| format!("invalid domain {domain}: {e}"))?;
+
+    let key_auth_digest = digest(&SHA256, key_auth.as_bytes());
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+        key_auth_digest.as_ref(),
+    )];
+
+    let cert = params
+        .self_signed(&key)
+        .map_err(|e| format!("unable to create challenge certificate: {e}"))?;
+
+    let provider = CryptoProvider::get_default()
+        .expect("The crypto provider is installed before ACME starts");
+
+    // `CertifiedKey::from_der` would parse the certificate, which fails on
+    // the critical acmeIdentifier extension
+    let signing_key = provider
+        .key_provider
+        .load_private_key(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            key.serialize_der(),
+        )))
+        .map_err(|e| format!("unusable challenge key: {e}"))?;
+
+    Ok(CertifiedKey::new(
+        vec![CertificateDer::from(cert.der().to_vec())],
+        signing_key,
+    ))
+}
+
+fn load_or_create_account_key(
+    settings: &AcmeSettings,
+) -> Result<EcdsaKeyPair, String> {
+    let path = settings.account_key_path();
+
+    let key = match std::fs::read_to_string(&path) {
+        Ok(pem) => KeyPair::from_pem(&pem).map_err(|e| {
+            format!("invalid account key \"{}\": {e}", path.display())
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("Creating ACME account key \"{}\"", path.display());
+            let key = new_key()?;
+            write_private(&path, key.serialize_pem().as_bytes())?;
+            key
+        }
+        Err(e) => {
+            return Err(format!(
+                "unable to read account key \"{}\": {e}",
+                path.display()
+            ))
+        }
+    };
+
+    EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        &key.serialize_der(),
+        &SystemRandom::new(),
+    )
+    .map_err(|e| format!("account key is not a P-256 key: {e}"))
+}
+
+fn new_key() -> Result<KeyPair, String> {
+    KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+        .map_err(|e| format!("unable to generate key: {e}"))
+}
+
+/// Write a file only the owner can read
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let staged = stage(path, contents, true)?;
+    replace(&staged, path)
+}
+
+/// Replace the certificate chain and key at `paths`. Both are written out in
+/// full before either is replaced. The certificate goes last, since a reload
+/// which catches the new key with the old certificate fails harmlessly and
+/// is tried again once the certificate changes
+fn write_cert_and_key(
+    paths: &CertPaths,
+    cert: &[u8],
+    key: &[u8],
+) -> Result<(), String> {
+    let (cert_path, key_path) = (Path::new(&paths.cert), Path::new(&paths.key));
+
+    let staged_key = stage(key_path, key, true)?;
+    let staged_cert = stage(cert_path, cert, false)?;
+
+    replace(&staged_key, key_path)?;
+    replace(&staged_cert, cert_path)
+}
+
+/// Write `contents` to a temporary file next to `path`, to be moved into
+/// place with [`replace`] so readers never see a partial file
+fn stage(
+    path: &Path,
+    contents: &[u8],
+    private: bool,
+) -> Result<PathBuf, String> {
+    let tmp = path.with_extension("tmp");
+    let mode = if private { 0o600 } else { 0o644 };
+
+    let write = || -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    };
+
+    write()
+        .map_err(|e| format!("unable to write \"{}\": {e}", tmp.display()))?;
+    Ok(tmp)
+}
+
+fn replace(staged: &Path, path: &Path) -> Result<(), String> {
+    std::fs::rename(staged, path)
+        .map_err(|e| format!("unable to write \"{}\": {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use rcgen::{CertifiedIssuer, IsCa};
+
+    use super::*;
+    use crate::{test_util, tls::load_certified_key};
+
+    fn settings(storage: &Path, domains: &[&str]) -> AcmeSettings {
+        AcmeSettings {
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+            directory: String::new(),
+            email: None,
+            storage: storage.to_owned(),
+            challenge: AcmeChallenge::Http01,
+            ca_cert: None,
+        }
+    }
+
+    /// Store a certificate for `domains` valid for `days`, issued by a CA
+    fn store_issued(settings: &AcmeSettings, domains: &[&str], days: i64) {
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, new_key().unwrap())
+            .unwrap();
+
+        let key = new_key().unwrap();
+        let domains = domains.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        let mut params = CertificateParams::new(domains).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now;
+        params.not_after = now + time::Duration::days(days);
+        let cert = params.signed_by(&key, &ca).unwrap();
+
+        write_cert_and_key(
+            &settings.cert_paths(),
+            cert.pem().as_bytes(),
+            key.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn placeholder_is_short_lived_and_replaced_at_once() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(dir.path(), &["example.com"]);
+
+        ensure_placeholder(&settings).unwrap();
+
+        let paths = settings.cert_paths();
+        load_certified_key(&paths).unwrap();
+
+        let pem = std::fs::read(&paths.cert).unwrap();
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).unwrap();
+        let cert = pem.parse_x509().unwrap();
+        let validity = cert.validity();
+        let lifetime =
+            validity.not_after.timestamp() - validity.not_before.timestamp();
+        assert!(lifetime <= 2 * 24 * 60 * 60);
+        assert!(validity.is_valid());
+
+        assert_eq!(renewal_wait(&settings), Duration::ZERO);
+
+        let mode = std::fs::metadata(&paths.key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn placeholder_keeps_existing_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(dir.path(), &["example.com"]);
+        store_issued(&settings, &["example.com"], 90);
+        let before = std::fs::read(settings.cert_paths().cert).unwrap();
+
+        ensure_placeholder(&settings).unwrap();
+
+        let after = std::fs::read(settings.cert_paths().cert).unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn issued_certificate_is_renewed_before_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings =
+            settings(dir.path(), &["example.com", "www.example.com"]);
+
+        // renewed 30 days before expiry
+        store_issued(&settings, &["example.com", "www.example.com"], 90);
+        let wait = renewal_wait(&settings).as_secs();
+        let day = 24 * 60 * 60;
+        assert!((59 * day..=60 * day).contains(&wait));
+
+        // short-lived certificates when a third of their lifetime is left
+        store_issued(&settings, &["example.com", "www.example.com"], 6);
+        let wait = renewal_wait(&settings).as_secs();
+        assert!((3 * day..=4 * day).contains(&wait));
+    }
+
+    #[test]
+    fn certificate_missing_a_domain_is_renewed() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings =
+            settings(dir.path(), &["example.com", "www.example.com"]);
+        store_issued(&settings, &["example.com"], 90);
+
+        assert_eq!(renewal_wait(&settings), Duration::ZERO);
+    }
+
+    #[test]
+    fn certificate_and_key_replace_leaves_no_staged_files() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let settings = settings(dir.path(), &["example.com"]);
+
+        store_issued(&settings, &["example.com"], 90);
+        store_issued(&settings, &["example.com"], 90);
+
+        load_certified_key(&settings.cert_paths()).unwrap();
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["cert.pem", "key.pem"]);
+    }
+
+    /// Gets a certificate from a Pebble test CA. Run Pebble with
+    /// `PEBBLE_VA_ALWAYS_VALID=1` so that challenges needn't be reachable, then
+    /// point `SLOT_TEST_PEBBLE_DIRECTORY` at its directory (e.g.,
+    /// "https://localhost:14000/dir") and `SLOT_TEST_PEBBLE_CA` at the CA
+    /// bundle its HTTPS server uses
+    #[tokio::test]
+    #[ignore = "needs a Pebble ACME server"]
+    async fn pebble_issues_certificate() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = settings(dir.path(), &["slot.example.com"]);
+        settings.directory = std::env::var("SLOT_TEST_PEBBLE_DIRECTORY")
+            .expect("SLOT_TEST_PEBBLE_DIRECTORY is set");
+        settings.ca_cert = std::env::var("SLOT_TEST_PEBBLE_CA").ok();
+
+        ensure_placeholder(&settings).unwrap();
+        let resolver =
+            CertResolver::load(settings.cert_paths(), Vec::new()).unwrap();
+        let tokens = Http01Tokens::default();
+        let http = build_http_client(&settings).unwrap();
+
+        issue_certificate(&settings, &http, &resolver, &tokens)
+            .await
+            .unwrap();
+
+        resolver.reload().unwrap();
+        assert!(!renewal_wait(&settings).is_zero());
+        assert!(tokens.read().unwrap().is_empty());
+    }
+}
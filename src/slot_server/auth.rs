@@ -0,0 +1,253 @@
+//! Authentication of joining modules against the secrets in the config
+
+use std::{collections::HashMap, sync::Arc};
+
+use slot_client::{
+    auth::{self, JoinSecret},
+    protocol::{JoinOptions, ValidName},
+    protocol2::RejectReason,
+};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+
+/// Checks the signatures of joins, remembering the nonces of recent ones so
+/// none is accepted twice
+#[derive(Clone)]
+pub struct JoinAuthenticator {
+    /// Module secrets by name
+    secrets: Arc<HashMap<String, JoinSecret>>,
+    required: bool,
+    max_age: u64,
+
+    /// Timestamps of accepted joins, by module name and nonce
+    seen: Arc<Mutex<HashMap<(String, u64), u64>>>,
+}
+
+impl JoinAuthenticator {
+    pub fn new(config: &Config) -> Self {
+        let secrets = config
+            .modules
+            .iter()
+            .filter_map(|(name, module)| {
+                let secret = module.secret.as_deref()?;
+                Some((name.clone(), JoinSecret::from(secret)))
+            })
+            .collect();
+
+        Self {
+            secrets: Arc::new(secrets),
+            required: config.auth.required,
+            max_age: config.auth.max_age,
+            seen: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Check a join as `name` serving HTTP on `http_port` with `options`.
+    /// Returns whether it was signed with the name's secret
+    pub async fn check(
+        &self,
+        name: &ValidName,
+        http_port: u16,
+        options: &JoinOptions,
+    ) -> Result<bool, RejectReason> {
+        let Some(secret) = self.secrets.get(&name.to_string()) else {
+            return if self.required {
+                Err(RejectReason::NotAllowed)
+            } else {
+                Ok(false)
+            };
+        };
+
+        let auth = options.auth.as_ref().ok_or(RejectReason::AuthRequired)?;
+
+        if !auth.verify(secret, name, http_port, options) {
+            return Err(RejectReason::BadSignature);
+        }
+
+        let now = auth::unix_time();
+        if now.abs_diff(auth.timestamp) > self.max_age {
+            return Err(RejectReason::Expired);
+        }
+
+        let mut seen = self.seen.lock().await;
+
+        // joins this old are rejected as expired, so their nonces can go
+        seen.retain(|_, timestamp| now.abs_diff(*timestamp) <= self.max_age);
+
+        if seen
+            .insert((name.to_string(), auth.nonce), auth.timestamp)
+            .is_some()
+        {
+            return Err(RejectReason::Replayed);
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slot_client::auth::JoinAuth;
+
+    use super::*;
+    use crate::config::ModuleConfig;
+
+    fn authenticator(max_age: u64) -> JoinAuthenticator {
+        let mut config = Config::default();
+        let module = ModuleConfig {
+            secret: Some("hunter2".to_owned()),
+            ..Default::default()
+        };
+        config.modules.insert("blog".to_owned(), module);
+        config.auth.max_age = max_age;
+        JoinAuthenticator::new(&config)
+    }
+
+    /// Options for a join as "blog" on port 8080, signed with `secret`
+    fn signed(secret: &str) -> JoinOptions {
+        let options = JoinOptions {
+            hosts: vec!["blog.example.com".to_owned()],
+            secret: Some(JoinSecret::from(secret)),
+            ..Default::default()
+        };
+        options.signed(&"blog".parse().unwrap(), 8080)
+    }
+
+    async fn check(
+        authenticator: &JoinAuthenticator,
+        name: &str,
+        options: &JoinOptions,
+    ) -> Result<bool, RejectReason> {
+        authenticator
+            .check(&name.parse().unwrap(), 8080, options)
+            .await
+    }
+
+    #[tokio::test]
+    async fn signed_join_is_accepted() {
+        let authenticator = authenticator(30);
+        assert_eq!(
+            check(&authenticator, "blog", &signed("hunter2")).await,
+            Ok(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn unsigned_join_is_refused() {
+        let authenticator = authenticator(30);
+        assert_eq!(
+            check(&authenticator, "blog", &JoinOptions::default()).await,
+            Err(RejectReason::AuthRequired)
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_mac_is_refused() {
+        let authenticator = authenticator(30);
+        assert_eq!(
+            check(&authenticator, "blog", &signed("hunter3")).await,
+            Err(RejectReason::BadSignature)
+        );
+
+        let mut options = signed("hunter2");
+        let mac = &mut options.auth.as_mut().unwrap().mac;
+        mac[0] ^= 1;
+        assert_eq!(
+            check(&authenticator, "blog", &options).await,
+            Err(RejectReason::BadSignature)
+        );
+    }
+
+    #[tokio::test]
+    async fn changed_options_are_refused() {
+        let authenticator = authenticator(30);
+
+        let mut options = signed("hunter2");
+        options.hosts.push("bank.example.com".to_owned());
+        assert_eq!(
+            check(&authenticator, "blog", &options).await,
+            Err(RejectReason::BadSignature)
+        );
+
+        let options = JoinOptions {
+            http_socket: Some("/tmp/other.sock".to_owned()),
+            ..signed("hunter2")
+        };
+        assert_eq!(
+            check(&authenticator, "blog", &options).await,
+            Err(RejectReason::BadSignature)
+        );
+    }
+
+    /// Options for a join as "blog" on port 8080, signed `age` seconds ago
+    fn signed_ago(age: i64) -> JoinOptions {
+        let secret = JoinSecret::from("hunter2");
+        let mut options = JoinOptions {
+            secret: Some(secret.clone()),
+            ..Default::default()
+        };
+        let timestamp = auth::unix_time().saturating_add_signed(-age);
+        options.auth = Some(JoinAuth::sign_at(
+            &secret,
+            &"blog".parse().unwrap(),
+            8080,
+            &options,
+            timestamp,
+        ));
+        options
+    }
+
+    #[tokio::test]
+    async fn stale_join_is_refused() {
+        let authenticator = authenticator(30);
+
+        assert_eq!(
+            check(&authenticator, "blog", &signed_ago(60)).await,
+            Err(RejectReason::Expired)
+        );
+        assert_eq!(
+            check(&authenticator, "blog", &signed_ago(-60)).await,
+            Err(RejectReason::Expired)
+        );
+        assert_eq!(
+            check(&authenticator, "blog", &signed_ago(10)).await,
+            Ok(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn replayed_nonce_is_refused() {
+        let authenticator = authenticator(30);
+        let options = signed("hunter2");
+
+        assert_eq!(check(&authenticator, "blog", &options).await, Ok(true));
+        assert_eq!(
+            check(&authenticator, "blog", &options).await,
+            Err(RejectReason::Replayed)
+        );
+
+        // a fresh signature is fine
+        assert_eq!(
+            check(&authenticator, "blog", &signed("hunter2")).await,
+            Ok(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn names_without_secret() {
+        let authenticator = authenticator(30);
+        assert_eq!(
+            check(&authenticator, "api", &JoinOptions::default()).await,
+            Ok(false)
+        );
+
+        let mut config = Config::default();
+        config.auth.required = true;
+        let authenticator = JoinAuthenticator::new(&config);
+        assert_eq!(
+            check(&authenticator, "api", &JoinOptions::default()).await,
+            Err(RejectReason::NotAllowed)
+        );
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! Adding attributes to this structure will add CLI options
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::net::IpAddr;
 
 const DEFAULT_LOG_LEVEL: &str = "INFO";
@@ -10,6 +10,16 @@ const DEFAULT_BIND: &str = "127.0.0.1";
 const DEFAULT_HTTP_PORT: &str = "8000";
 const DEFAULT_HTTPS_PORT: &str = "8001";
 const DEFAULT_SLOT_BIND: &str = "7568";
+const DEFAULT_UPSTREAM_MAX_IDLE: &str = "8";
+const DEFAULT_UPSTREAM_CONNECT_TIMEOUT: &str = "5";
+const DEFAULT_UPSTREAM_READ_TIMEOUT: &str = "30";
+const DEFAULT_MAX_CONNECTIONS: &str = "4096";
+const DEFAULT_MAX_HANDSHAKES: &str = "256";
+const DEFAULT_HANDSHAKE_TIMEOUT: &str = "10";
+const DEFAULT_HANDSHAKE_LOG_LEVEL: &str = "DEBUG";
+const DEFAULT_ACME_DIRECTORY: &str =
+    "https://acme-v02.api.letsencrypt.org/directory";
+const DEFAULT_ACME_STORAGE: &str = "acme";
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about = "Slot server")]
@@ -34,17 +44,137 @@ pub struct Args {
     #[arg(short='s', long="slot-bind", default_value=DEFAULT_SLOT_BIND)]
     pub slot_port: u16,
 
-    /// The PEM website certificate and public key for SSL
-    #[arg(short = 'c', long = "cert")]
-    pub cert_file: String,
+    /// Which listeners serve content: "https" serves over HTTPS and redirects
+    /// HTTP to it, "http" serves plain HTTP only (e.g., for development or
+    /// behind a TLS terminator) and "both" serves over both
+    #[arg(short = 'm', long = "mode", value_enum, default_value = "https")]
+    pub mode: ServeMode,
+
+    /// The PEM website certificate and public key for SSL. Not needed when
+    /// certificates come from ACME or TLS is off
+    #[arg(short = 'c', long = "cert", requires = "key_file")]
+    pub cert_file: Option<String>,
 
     /// The PEM private key for SSL
-    #[arg(short = 'k', long = "key")]
-    pub key_file: String,
+    #[arg(short = 'k', long = "key", requires = "cert_file")]
+    pub key_file: Option<String>,
+
+    /// A DER encoded OCSP response to staple to the certificate given with
+    /// --cert
+    #[arg(long = "ocsp", requires = "cert_file")]
+    pub ocsp_file: Option<String>,
+
+    /// Get a certificate for this domain from an ACME certificate authority.
+    /// Can be given multiple times
+    #[arg(long = "acme-domain")]
+    pub acme_domains: Vec<String>,
+
+    /// The ACME directory URL, e.g., Let's Encrypt's staging environment for
+    /// testing
+    #[arg(long="acme-directory", default_value=DEFAULT_ACME_DIRECTORY)]
+    pub acme_directory: String,
+
+    /// Contact email for the ACME account
+    #[arg(long = "acme-email")]
+    pub acme_email: Option<String>,
+
+    /// Directory where the ACME account key and certificate are kept
+    #[arg(long="acme-storage", default_value=DEFAULT_ACME_STORAGE)]
+    pub acme_storage: String,
+
+    /// How the ACME server validates the domains. "http-01" needs the HTTP
+    /// server on port 80, "tls-alpn-01" the HTTPS server on port 443
+    #[arg(long = "acme-challenge", value_enum, default_value = "http-01")]
+    pub acme_challenge: AcmeChallenge,
+
+    /// Additional PEM root certificate to trust for the ACME directory, e.g.,
+    /// that of a local test server
+    #[arg(long = "acme-ca-cert")]
+    pub acme_ca_cert: Option<String>,
+
+    /// TOML file with additional settings, such as per-module options
+    #[arg(short = 'C', long = "config")]
+    pub config_file: Option<String>,
+
+    /// Directory of templates for the error pages Slot serves itself, e.g.,
+    /// "502.html", "error.html" or "error.json"
+    #[arg(short = 'e', long = "error-pages")]
+    pub error_pages_dir: Option<String>,
 
     /// The route that "/" redirects to. This allows the default route to
     /// redirect to a module route since the Slot server itself provides no
     /// content.
     #[arg(short = 'r', long = "default-redirect")]
     pub default_redirect: String,
+
+    /// Maximum number of idle connections kept open to each module
+    #[arg(long="upstream-max-idle", default_value=DEFAULT_UPSTREAM_MAX_IDLE)]
+    pub upstream_max_idle: usize,
+
+    /// Seconds to wait for a connection to a module to be established
+    #[arg(
+        long = "upstream-connect-timeout",
+        default_value = DEFAULT_UPSTREAM_CONNECT_TIMEOUT
+    )]
+    pub upstream_connect_timeout: u64,
+
+    /// Seconds a module may stay silent while responding before the request
+    /// is abandoned. This is reset whenever the module sends data, so
+    /// streaming responses are not cut off
+    #[arg(
+        long = "upstream-read-timeout",
+        default_value = DEFAULT_UPSTREAM_READ_TIMEOUT
+    )]
+    pub upstream_read_timeout: u64,
+
+    /// Seconds a module may take for a whole response, body included. No
+    /// limit when not given
+    #[arg(long = "upstream-total-timeout")]
+    pub upstream_total_timeout: Option<u64>,
+
+    /// Talk to modules using HTTP/2 without negotiation. Every module must
+    /// then accept HTTP/2 cleartext connections
+    #[arg(long = "upstream-http2")]
+    pub upstream_http2: bool,
+
+    /// Maximum number of client connections served at once, over all
+    /// listeners. Further connections wait to be accepted
+    #[arg(long = "max-connections", default_value = DEFAULT_MAX_CONNECTIONS)]
+    pub max_connections: usize,
+
+    /// Maximum number of TLS and QUIC handshakes in progress at once
+    #[arg(long = "max-handshakes", default_value = DEFAULT_MAX_HANDSHAKES)]
+    pub max_handshakes: usize,
+
+    /// Seconds a client may take to complete the TLS or QUIC handshake
+    #[arg(
+        long = "handshake-timeout",
+        default_value = DEFAULT_HANDSHAKE_TIMEOUT
+    )]
+    pub handshake_timeout: u64,
+
+    /// Level at which failed TLS and QUIC handshakes are logged (ERROR, WARN,
+    /// INFO, DEBUG, TRACE)
+    #[arg(
+        long = "handshake-log-level",
+        default_value = DEFAULT_HANDSHAKE_LOG_LEVEL
+    )]
+    pub handshake_log_level: log::Level,
+}
+
+/// The ACME challenge types Slot can answer
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    #[value(name = "http-01")]
+    Http01,
+    #[value(name = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// Which of the public listeners serve content
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServeMode {
+    Https,
+    Http,
+    Both,
 }
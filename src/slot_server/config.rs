@@ -0,0 +1,340 @@
+//! Defines the optional configuration file
+//!
+//! Settings which don't fit on the command line, such as per-module options,
+//! are read from a TOML file given with `--config`
+
+use std::{collections::HashMap, time::Duration};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Per-module settings keyed by module name
+    pub modules: HashMap<String, ModuleConfig>,
+
+    pub tls: TlsConfig,
+
+    pub redirect: RedirectConfig,
+
+    pub hsts: HstsConfig,
+
+    pub proxy_protocol: ProxyProtocolConfig,
+
+    pub control: ControlConfig,
+
+    pub auth: AuthConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Additional certificates, chosen by the server name (SNI) the client
+    /// asks for. Clients that ask for a name none of these cover get the
+    /// `--cert` certificate
+    pub certificates: Vec<CertPaths>,
+
+    /// PEM bundle of the CAs whose client certificates are accepted. Clients
+    /// are only asked for a certificate when this is set
+    pub client_ca: Option<String>,
+
+    /// Oldest TLS version clients may use
+    pub min_version: TlsVersion,
+
+    /// Cipher suites clients may use, by name, e.g.,
+    /// "TLS13_AES_256_GCM_SHA384". Every suite rustls supports when empty
+    pub cipher_suites: Vec<String>,
+
+    /// Number of sessions kept for resumption. Zero turns stateful resumption
+    /// off
+    pub session_cache_size: usize,
+
+    /// Resume sessions with encrypted tickets instead of the session cache
+    pub session_tickets: bool,
+
+    /// Seconds each session ticket key is used before a new one is made. At
+    /// most 6 hours
+    pub session_ticket_rotation: u32,
+}
+
+/// Longest time a session ticket key may be used for
+pub const MAX_TICKET_ROTATION: u32 = 6 * 60 * 60;
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            certificates: Vec::new(),
+            client_ca: None,
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+            session_cache_size: 256,
+            session_tickets: false,
+            session_ticket_rotation: MAX_TICKET_ROTATION,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// How plain HTTP requests are sent to HTTPS when serving `--mode https`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    /// The port clients reach HTTPS on, when it differs from `--https-bind`,
+    /// e.g., behind port forwarding. Port 443 is left out of the new URL
+    pub port: Option<u16>,
+
+    /// Whether browsers may remember the redirect (308) or not (307)
+    pub permanent: bool,
+
+    /// Path prefixes which are served over plain HTTP instead of redirected.
+    /// Requests under them go to the modules just like HTTPS requests do, so
+    /// whatever modules serve there is readable and modifiable in transit.
+    /// ACME challenges are always answered and needn't be listed
+    pub exclude: Vec<String>,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        Self {
+            port: None,
+            permanent: true,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// The `Strict-Transport-Security` header added to every HTTPS response
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HstsConfig {
+    /// Seconds browsers must only use HTTPS for. No header is sent when unset
+    pub max_age: Option<u64>,
+
+    pub include_subdomains: bool,
+
+    /// Ask to be included in browsers' built-in HSTS lists
+    pub preload: bool,
+}
+
+/// PROXY protocol headers sent by TCP load balancers in front of Slot
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    /// Networks of the load balancers, e.g., "10.0.0.0/8". Connections from
+    /// them must start with a PROXY protocol v1 or v2 header, and the client
+    /// address in it is used instead of the balancer's. Headers from anywhere
+    /// else aren't accepted
+    pub trusted: Vec<IpNet>,
+}
+
+/// Where modules register with Slot
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Whether modules may register over UDP and TCP on `--slot-port`, which
+    /// any local process can reach
+    pub localhost: bool,
+
+    /// Path of a Unix socket modules may register over, speaking version 2 of
+    /// the protocol. A stale socket left at the path is replaced
+    pub socket: Option<String>,
+
+    /// Permissions of the socket file, e.g., 0o660. Connecting needs write
+    /// permission
+    pub socket_mode: u32,
+
+    /// Users allowed to register over the socket, by UID. Anyone who can
+    /// connect may register when both this and `allowed_gids` are empty
+    pub allowed_uids: Vec<u32>,
+
+    /// Groups allowed to register over the socket, by the primary GID of the
+    /// connecting process
+    pub allowed_gids: Vec<u32>,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            localhost: true,
+            socket: None,
+            socket_mode: 0o600,
+            allowed_uids: Vec::new(),
+            allowed_gids: Vec::new(),
+        }
+    }
+}
+
+/// Authentication of modules when they join
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Only register modules with a `secret` under `[modules]`. Otherwise
+    /// modules without one may join unsigned, as long as their name is free
+    pub required: bool,
+
+    /// Seconds a signed join stays valid for, which also allows for that much
+    /// difference between the clocks of Slot and the module
+    pub max_age: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            required: false,
+            max_age: 30,
+        }
+    }
+}
+
+/// A PEM certificate chain and its private key
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CertPaths {
+    pub cert: String,
+    pub key: String,
+
+    /// DER encoded OCSP response to staple. Reloaded with the certificate
+    /// whenever it changes, so it can be refreshed by an external job
+    pub ocsp: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModuleConfig {
+    /// Seconds the module may stay silent while responding. Overrides
+    /// `--upstream-read-timeout`
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds the module may take for a whole response, including the body.
+    /// Overrides `--upstream-total-timeout`
+    pub total_timeout: Option<u64>,
+
+    /// Whether clients must present a certificate issued by one of the
+    /// `tls.client_ca` CAs to reach the module
+    pub client_auth: ClientAuth,
+
+    /// Secret the module signs its joins with. Joins under this name which
+    /// aren't signed with it are rejected
+    pub secret: Option<String>,
+}
+
+/// How a module treats client certificates
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Requests without a verified client certificate are refused
+    Required,
+
+    /// The certificate's subject is passed on when there is one
+    Optional,
+
+    /// Client certificates are ignored
+    #[default]
+    Off,
+}
+
+/// How long a module may take to answer a forwarded request
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub idle: Duration,
+    pub total: Option<Duration>,
+}
+
+impl Config {
+    /// Read the configuration file, or use the defaults when there is none
+    pub fn load(path: Option<&str>) -> Self {
+        let Some(path) = path else {
+            return Self::default();
+        };
+
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!("Failed to read config file \"{path}\": \"{e}\"");
+                std::process::exit(1);
+            }
+        };
+
+        let config: Self = match toml::from_str(&text) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Invalid config file \"{path}\": \"{e}\"");
+                std::process::exit(1);
+            }
+        };
+
+        if config.tls.client_ca.is_none() {
+            let uses_client_auth = config
+                .modules
+                .iter()
+                .find(|(_, m)| m.client_auth != ClientAuth::Off);
+
+            if let Some((name, _)) = uses_client_auth {
+                log::error!(
+                    "Module \"{name}\" uses client certificates, but \
+                     tls.client_ca is not set in \"{path}\""
+                );
+                std::process::exit(1);
+            }
+        }
+
+        let rotation = config.tls.session_ticket_rotation;
+        if !(1..=MAX_TICKET_ROTATION).contains(&rotation) {
+            log::error!(
+                "tls.session_ticket_rotation must be between 1 and \
+                 {MAX_TICKET_ROTATION} seconds in \"{path}\", not {rotation}"
+            );
+            std::process::exit(1);
+        }
+
+        if !config.control.localhost && config.control.socket.is_none() {
+            log::error!(
+                "control.localhost is off, but no control.socket is set in \
+                 \"{path}\", so no module could register"
+            );
+            std::process::exit(1);
+        }
+
+        if config.control.socket_mode > 0o777 {
+            log::error!(
+                "control.socket_mode {:#o} in \"{path}\" is not a permission \
+                 mode",
+                config.control.socket_mode
+            );
+            std::process::exit(1);
+        }
+
+        if config.auth.max_age == 0 {
+            log::error!("auth.max_age must be at least 1 second in \"{path}\"");
+            std::process::exit(1);
+        }
+
+        let has_secrets = config.modules.values().any(|m| m.secret.is_some());
+        if config.auth.required && !has_secrets {
+            log::error!(
+                "auth.required is on, but no module has a secret in \
+                 \"{path}\", so no module could register"
+            );
+            std::process::exit(1);
+        }
+
+        config
+    }
+
+    pub fn module(&self, name: &str) -> Option<&ModuleConfig> {
+        self.modules.get(name)
+    }
+
+    pub fn client_auth(&self, name: &str) -> ClientAuth {
+        self.module(name).map(|m| m.client_auth).unwrap_or_default()
+    }
+}
@@ -0,0 +1,525 @@
+//! Gateway errors and the pages shown to clients for them
+//!
+//! Operators can replace the built-in pages by putting templates in the
+//! directory given with `--error-pages`. For each response Slot looks for
+//! `{status}.html` (e.g., "502.html"), then `error.html`, and falls back to
+//! the built-in page. Clients that prefer `application/json` get the `.json`
+//! templates instead. Templates may contain the placeholders `{{status}}`,
+//! `{{reason}}`, `{{module}}` and `{{message}}`.
+
+use std::{collections::HashMap, path::Path};
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+
+/// Seconds clients are told to wait before retrying a module that left
+const RETRY_AFTER_SECS: u32 = 10;
+
+const DEFAULT_HTML: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>{{message}}</p>
+</body>
+</html>
+";
+
+const DEFAULT_JSON: &str = r#"{"status":{{status}},"reason":"{{reason}}","module":"{{module}}","message":"{{message}}"}
+"#;
+
+/// Reasons a request could not be forwarded to a module
+#[derive(Debug)]
+pub enum ProxyError {
+    /// No module by this name has joined
+    NoSuchModule,
+
+    /// The module was registered but has since left
+    ModuleGone,
+
+    /// A connection to the module could not be made
+    Unreachable(String),
+
+    /// The module did not respond in time
+    Timeout,
+
+    /// The module's response could not be used
+    BadResponse(String),
+
+    /// The module requires a client certificate and the client sent none
+    ClientCertRequired,
+}
+
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::NoSuchModule => StatusCode::NOT_FOUND,
+            ProxyError::ModuleGone => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::Unreachable(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::BadResponse(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::ClientCertRequired => StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// Client-facing description of the error
+    pub fn message(&self, modname: &str) -> String {
+        match self {
+            ProxyError::NoSuchModule => {
+                format!("Module \"{modname}\" does not exist")
+            }
+            ProxyError::ModuleGone => {
+                format!("Module \"{modname}\" is offline")
+            }
+            ProxyError::Unreachable(_) => {
+                format!("Module \"{modname}\" could not be reached")
+            }
+            ProxyError::Timeout => {
+                format!("Module \"{modname}\" did not respond in time")
+            }
+            ProxyError::BadResponse(_) => {
+                format!("Module \"{modname}\" sent an invalid response")
+            }
+            ProxyError::ClientCertRequired => {
+                format!("Module \"{modname}\" requires a client certificate")
+            }
+        }
+    }
+
+    /// Operator-facing description of what went wrong
+    pub fn cause(&self) -> &str {
+        match self {
+            ProxyError::NoSuchModule => "not registered",
+            ProxyError::ModuleGone => "deregistered",
+            ProxyError::Unreachable(e) => e,
+            ProxyError::Timeout => "timed out",
+            ProxyError::BadResponse(e) => e,
+            ProxyError::ClientCertRequired => "no client certificate",
+        }
+    }
+}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            ProxyError::Timeout
+        } else if e.is_connect() {
+            ProxyError::Unreachable(error_chain(&e))
+        } else {
+            ProxyError::BadResponse(error_chain(&e))
+        }
+    }
+}
+
+/// Describe an error along with everything that caused it, since reqwest's
+/// own messages leave out the underlying reason
+pub fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut desc = e.to_string();
+    let mut source = e.source();
+
+    while let Some(e) = source {
+        desc.push_str(": ");
+        desc.push_str(&e.to_string());
+        source = e.source();
+    }
+
+    desc
+}
+
+/// Error page templates, keyed by file name
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    templates: HashMap<String, String>,
+}
+
+impl ErrorPages {
+    /// Read all templates from `dir`, or use the built-in pages when there is
+    /// no directory
+    pub fn load(dir: Option<&str>) -> Self {
+        let Some(dir) = dir else {
+            return Self::default();
+        };
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(e) => {
+                log::error!(
+                    "Failed to open error page directory \"{dir}\": \"{e}\""
+                );
+                std::process::exit(1);
+            }
+        };
+
+        let mut templates = HashMap::new();
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_template = path
+                .extension()
+                .is_some_and(|ext| ext == "html" || ext == "json");
+
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str())
+            else {
+                continue;
+            };
+
+            if !is_template {
+                continue;
+            }
+
+            match std::fs::read_to_string(&path) {
+                Ok(t) => {
+                    log::debug!("Loaded error page template \"{file_name}\"");
+                    templates.insert(file_name.to_owned(), t);
+                }
+                Err(e) => log::warn!(
+                    "Failed to read error page template \"{}\": \"{e}\"",
+                    Path::new(dir).join(file_name).display()
+                ),
+            }
+        }
+
+        Self { templates }
+    }
+
+    /// Log a forwarding error and build the page shown to the client for it
+    pub fn proxy_error(
+        &self,
+        err: &ProxyError,
+        modname: &str,
+        req_headers: &HeaderMap,
+    ) -> Response {
+        log::warn!(
+            "Request to module \"{modname}\" failed with {}: {}",
+            err.status(),
+            err.cause()
+        );
+
+        let mut resp = self.render(
+            err.status(),
+            Some(modname),
+            &err.message(modname),
+            req_headers,
+        );
+
+        if let ProxyError::ModuleGone = err {
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, RETRY_AFTER_SECS.into());
+        }
+
+        resp
+    }
+
+    /// Build an error page, in JSON if the client prefers it
+    pub fn render(
+        &self,
+        status: StatusCode,
+        modname: Option<&str>,
+        message: &str,
+        req_headers: &HeaderMap,
+    ) -> Response {
+        let json = prefers_json(req_headers);
+
+        let (ext, content_type, default) = if json {
+            ("json", "application/json", DEFAULT_JSON)
+        } else {
+            ("html", "text/html; charset=utf-8", DEFAULT_HTML)
+        };
+
+        let escape =
+            |s: &str| if json { escape_json(s) } else { escape_html(s) };
+
+        let template = self
+            .templates
+            .get(&format!("{}.{ext}", status.as_u16()))
+            .or_else(|| self.templates.get(&format!("error.{ext}")))
+            .map(String::as_str)
+            .unwrap_or(default);
+
+        let body = template
+            .replace("{{status}}", status.as_str())
+            .replace(
+                "{{reason}}",
+                &escape(status.canonical_reason().unwrap_or_default()),
+            )
+            .replace("{{module}}", &escape(modname.unwrap_or_default()))
+            .replace("{{message}}", &escape(message));
+
+        Response::builder()
+            .status(status)
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(content_type),
+            )
+            .body(body.into())
+            .unwrap()
+    }
+}
+
+/// Whether the client's `Accept` header ranks JSON above HTML
+fn prefers_json(req_headers: &HeaderMap) -> bool {
+    let mut json_q = 0.0;
+    let mut html_q = 0.0;
+
+    let ranges = req_headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+
+    for range in ranges {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match media {
+            "application/json" => json_q = q,
+            "text/html" => html_q = q,
+            _ => {}
+        }
+    }
+
+    json_q > html_q
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                out.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, extract::Request, Router};
+    use slot_client::protocol::JoinOptions;
+
+    use super::*;
+    use crate::{
+        config::{Config, ModuleConfig},
+        store::{ControlProtocol, HttpAddr, ModuleStore, SlotAddr},
+        test_util,
+    };
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_bad_gateway() {
+        // a port nothing listens on any more
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap();
+
+        let modules = ModuleStore::new();
+        test_util::add_module(
+            &modules,
+            "mod",
+            HttpAddr::Tcp(addr),
+            JoinOptions::default(),
+        )
+        .await;
+        let slot = test_util::slot(modules, Config::default());
+
+        let (status, body) = test_util::get(&slot, "/mod/").await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(body.contains("Module &quot;mod&quot; could not be reached"));
+    }
+
+    #[tokio::test]
+    async fn departed_module_is_unavailable_for_now() {
+        let modules = ModuleStore::new();
+        test_util::add_module(
+            &modules,
+            "mod",
+            HttpAddr::Tcp(([127, 0, 0, 1], 1).into()),
+            JoinOptions::default(),
+        )
+        .await;
+        let slot_addr = SlotAddr::Inet(([127, 0, 0, 1], 1).into());
+        modules.remove_module(&slot_addr, ControlProtocol::V2).await;
+        let slot = test_util::slot(modules, Config::default());
+
+        let req = Request::get("/mod/").body(Body::empty()).unwrap();
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            resp.headers()[header::RETRY_AFTER],
+            RETRY_AFTER_SECS.to_string()
+        );
+
+        // modules which never joined don't exist
+        let req = Request::get("/other/").body(Body::empty()).unwrap();
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(!resp.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn slow_module_is_gateway_timeout() {
+        let module = Router::new().fallback(async || {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            "too late"
+        });
+        let mut config = Config::default();
+        let module_config = ModuleConfig {
+            idle_timeout: Some(1),
+            ..Default::default()
+        };
+        config.modules.insert("mod".to_owned(), module_config);
+        let slot =
+            test_util::slot_with_module(module, JoinOptions::default(), config)
+                .await;
+
+        let (status, _) = test_util::get(&slot, "/mod/").await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn accept_header_picks_json() {
+        assert!(prefers_json(&accept("application/json")));
+        assert!(prefers_json(&accept("text/html;q=0.5, application/json")));
+        assert!(prefers_json(&accept(
+            "application/json;q=0.9, text/html;q=0.1"
+        )));
+
+        assert!(!prefers_json(&HeaderMap::new()));
+        assert!(!prefers_json(&accept("*/*")));
+        assert!(!prefers_json(&accept("text/html, application/json")));
+        assert!(!prefers_json(&accept("text/html,application/json;q=0.9")));
+    }
+
+    #[tokio::test]
+    async fn json_page_for_json_clients() {
+        let pages = ErrorPages::default();
+        let resp = pages.proxy_error(
+            &ProxyError::Timeout,
+            "mod",
+            &accept("application/json"),
+        );
+
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = test_util::body_text(resp).await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "status": 504,
+                "reason": "Gateway Timeout",
+                "module": "mod",
+                "message": "Module \"mod\" did not respond in time",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn templates_are_looked_up_by_status_then_generic() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, template: &str| {
+            std::fs::write(dir.path().join(name), template).unwrap();
+        };
+        write("502.html", "<b>{{status}}</b> {{module}}: {{message}}");
+        write("error.html", "{{status}} {{reason}}");
+        write("error.json", r#"{"oops":"{{message}}"}"#);
+        write("notes.txt", "not a template");
+
+        let pages = ErrorPages::load(dir.path().to_str());
+        let render = |status, headers: &HeaderMap| {
+            pages.render(status, Some("mod"), "It broke", headers)
+        };
+
+        let resp = render(StatusCode::BAD_GATEWAY, &HeaderMap::new());
+        assert_eq!(
+            test_util::body_text(resp).await,
+            "<b>502</b> mod: It broke"
+        );
+
+        let resp = render(StatusCode::GATEWAY_TIMEOUT, &HeaderMap::new());
+        assert_eq!(test_util::body_text(resp).await, "504 Gateway Timeout");
+
+        let resp = render(StatusCode::BAD_GATEWAY, &accept("application/json"));
+        assert_eq!(test_util::body_text(resp).await, r#"{"oops":"It broke"}"#);
+
+        assert_eq!(pages.templates.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn placeholders_are_escaped() {
+        let pages = ErrorPages::default();
+        let message = r#"<script>alert("a & b")</script>"#;
+
+        let resp = pages.render(
+            StatusCode::NOT_FOUND,
+            Some("<mod>"),
+            message,
+            &HeaderMap::new(),
+        );
+        let body = test_util::body_text(resp).await;
+        assert!(body.contains(
+            "<p>&lt;script&gt;alert(&quot;a &amp; b&quot;)&lt;/script&gt;</p>"
+        ));
+        assert!(!body.contains("<script>"));
+
+        let resp = pages.render(
+            StatusCode::NOT_FOUND,
+            Some(r#"m"o\d"#),
+            message,
+            &accept("application/json"),
+        );
+        let body = test_util::body_text(resp).await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["module"], r#"m"o\d"#);
+        assert_eq!(json["message"], message);
+    }
+
+    #[test]
+    fn escape_html_escapes_markup() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("plain"), "plain");
+    }
+
+    #[test]
+    fn escape_json_escapes_quotes_and_controls() {
+        assert_eq!(escape_json(r#"say "hi" & <go>"#), r#"say \"hi\" & <go>"#);
+        assert_eq!(escape_json("a\\b"), r"a\\b");
+        assert_eq!(escape_json("line\nbreak\u{7}"), r"line\u000abreak\u0007");
+    }
+}
@@ -0,0 +1,1541 @@
+//! Forwarding of web requests to Slot modules
+
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, Path, Request, State},
+    http::{
+        header,
+        uri::{Authority, Scheme},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    BoxError,
+};
+use futures::{Stream, StreamExt};
+use slot_client::{
+    forwarded,
+    protocol::{PathPolicy, RootPolicy},
+};
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{
+    config::{ClientAuth, Timeouts},
+    error::ProxyError,
+    state::AppState,
+    store::{HttpAddr, ModuleInfo},
+    tunnel,
+};
+
+/// Headers which only apply to a single connection and must not be forwarded
+/// by a proxy (RFC 9110 section 7.6.1)
+const HOP_BY_HOP: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+];
+
+/// Copy the end-to-end headers from `headers`, dropping the hop-by-hop headers
+/// as well as any header the sender listed in its `Connection` header
+pub fn end_to_end_headers(headers: &HeaderMap) -> HeaderMap {
+    // `Connection` may name additional headers which are hop-by-hop for this
+    // particular connection
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    let mut filtered = HeaderMap::with_capacity(headers.len());
+
+    for (k, v) in headers.iter() {
+        if HOP_BY_HOP.contains(k)
+            || k == header::UPGRADE
+            || listed.iter().any(|l| l == k.as_str())
+        {
+            continue;
+        }
+
+        filtered.append(k, v.clone());
+    }
+
+    filtered
+}
+
+/// Handles "/{modname}/{*rest}"
+pub async fn module_redirect(
+    State(state): State<AppState>,
+    Path((modname, _)): Path<(String, String)>,
+    req: Request,
+) -> Response {
+    // the extracted rest is percent-decoded, which would let "%3F" or "%2F"
+    // change the meaning of the path, so the raw one is forwarded instead
+    let modurl = raw_rest(req.uri().path());
+    respond(&state, &modname, Some(&modurl), req).await
+}
+
+/// Handles "/{modname}/", which the wildcard route doesn't match
+pub async fn module_index(
+    State(state): State<AppState>,
+    Path(modname): Path<String>,
+    req: Request,
+) -> Response {
+    respond(&state, &modname, Some(""), req).await
+}
+
+/// Handles the bare module root "/{modname}" according to the module's
+/// `RootPolicy`
+pub async fn module_root(
+    State(state): State<AppState>,
+    Path(modname): Path<String>,
+    req: Request,
+) -> Response {
+    respond(&state, &modname, None, req).await
+}
+
+/// Slot's own response for paths no other route matches
+pub async fn not_found(
+    State(state): State<AppState>,
+    req: Request,
+) -> Response {
+    log::debug!("No route for \"{}\"", req.uri().path());
+
+    state.error_pages.render(
+        StatusCode::NOT_FOUND,
+        None,
+        "The requested page does not exist",
+        req.headers(),
+    )
+}
+
+async fn respond(
+    state: &AppState,
+    modname: &str,
+    modurl: Option<&str>,
+    req: Request,
+) -> Response {
+    // kept for the error page, since the request is consumed when forwarding
+    let req_headers = req.headers().clone();
+
+    match forward(state, modname, modurl, req).await {
+        Ok(resp) => resp,
+        Err(e) => state.error_pages.proxy_error(&e, modname, &req_headers),
+    }
+}
+
+/// The part of `path` after its first segment, still percent-encoded. Dot
+/// segments are resolved within it, so it can't climb above the module's base
+/// path
+fn raw_rest(path: &str) -> String {
+    let rest = path
+        .trim_start_matches('/')
+        .split_once('/')
+        .map_or("", |(_, rest)| rest);
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+
+    for segment in rest.split('/') {
+        let decoded = segment.to_ascii_lowercase().replace("%2e", ".");
+        trailing_slash = decoded == "." || decoded == "..";
+
+        match decoded.as_str() {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut rest = segments.join("/");
+    if trailing_slash && !rest.is_empty() {
+        rest.push('/');
+    }
+    rest
+}
+
+/// Forward `req` to the module named `modname`. `modurl` is the raw rest of
+/// the path after "/{modname}/", or `None` for the bare module root
+async fn forward(
+    state: &AppState,
+    modname: &str,
+    modurl: Option<&str>,
+    req: Request,
+) -> Result<Response, ProxyError> {
+    // use the first segment of the URL endpoint to look up the module
+    let module_info = state.modules.find_module_by_name(modname).await;
+
+    let Some(module_info) = module_info else {
+        return Err(if state.modules.has_departed(modname).await {
+            ProxyError::ModuleGone
+        } else {
+            ProxyError::NoSuchModule
+        });
+    };
+
+    let query = req
+        .uri()
+        .query()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+
+    if modurl.is_none() && module_info.options.root == RootPolicy::Redirect {
+        return Ok(
+            Redirect::temporary(&format!("/{modname}/{query}")).into_response()
+        );
+    }
+
+    // the rest of the path never contains the query string so it is appended
+    // separately
+    let mut path = match (&module_info.options.path, modurl) {
+        (PathPolicy::Keep, Some(rest)) => format!("/{modname}/{rest}"),
+        (PathPolicy::Keep, None) => format!("/{modname}"),
+        (PathPolicy::Strip, rest) => format!("/{}", rest.unwrap_or_default()),
+        (PathPolicy::Rewrite(base), Some(rest)) => {
+            format!("{}/{rest}", base.trim_end_matches('/'))
+        }
+        (PathPolicy::Rewrite(base), None) => base.clone(),
+    };
+
+    path.push_str(&query);
+
+    forward_to(state, &module_info, path, Some(&format!("/{modname}")), req)
+        .await
+}
+
+/// Sends requests for hostnames claimed by a module straight to that module
+/// with their path unchanged. Everything else is left to the path routes
+pub async fn route_by_host(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let module_info = match request_host(&req) {
+        Some(host) => state.modules.find_module_by_host(&host).await,
+        None => None,
+    };
+
+    let Some(module_info) = module_info else {
+        return next.run(req).await;
+    };
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| "/".to_owned(), |p| p.as_str().to_owned());
+
+    // kept for the error page, since the request is consumed when forwarding
+    let req_headers = req.headers().clone();
+
+    match forward_to(&state, &module_info, path, None, req).await {
+        Ok(resp) => resp,
+        Err(e) => state.error_pages.proxy_error(
+            &e,
+            &module_info.name.to_string(),
+            &req_headers,
+        ),
+    }
+}
+
+/// Perform request forwarding to a module. `path` includes the query string.
+/// `prefix` is the path prefix the client used to reach the module, if any
+async fn forward_to(
+    state: &AppState,
+    module_info: &ModuleInfo,
+    path: String,
+    prefix: Option<&str>,
+    req: Request,
+) -> Result<Response, ProxyError> {
+    let modname = module_info.name.to_string();
+
+    log::debug!("Redirecting request to module \"{modname}\"");
+
+    let client_cert = match state.config.client_auth(&modname) {
+        ClientAuth::Off => None,
+        ClientAuth::Optional => req.extensions().get::<ClientCert>().cloned(),
+        ClientAuth::Required => Some(
+            req.extensions()
+                .get::<ClientCert>()
+                .cloned()
+                .ok_or(ProxyError::ClientCertRequired)?,
+        ),
+    };
+
+    let forwarding = forwarding_headers(&req, prefix, client_cert.as_ref());
+
+    if tunnel::is_upgrade_request(req.headers()) {
+        return tunnel::forward_upgrade(
+            modname,
+            &module_info.http_addr,
+            path,
+            forwarding,
+            state.connect_timeout,
+            req,
+        )
+        .await;
+    }
+
+    let (parts, body) = req.into_parts();
+
+    let mut headers = end_to_end_headers(&parts.headers);
+    set_forwarding_headers(&mut headers, forwarding);
+
+    let timer = ResponseTimer::start(state.timeouts(&modname));
+    let (body, uploaded) = timed_upload(body, timer, modname.clone());
+
+    let mod_resp = match &module_info.http_addr {
+        HttpAddr::Tcp(addr) => {
+            let url = format!("http://{addr}{path}");
+
+            // reqwest derives the host from the module's address
+            headers.remove(header::HOST);
+
+            let mut mod_req =
+                state.upstream.request(parts.method, url).headers(headers);
+
+            // stream the body through rather than collecting it. bodiless
+            // requests are left alone so they aren't sent as an empty chunked
+            // body
+            if !body.is_end_stream() {
+                mod_req = mod_req
+                    .body(reqwest::Body::wrap_stream(body.into_data_stream()));
+            }
+
+            let send = async { Ok(mod_req.send().await?) };
+            let mod_resp = await_response(send, uploaded, timer).await?;
+
+            axum::http::Response::from(mod_resp).map(Body::new)
+        }
+        http_addr @ HttpAddr::Unix { .. } => {
+            if let Ok(host) = HeaderValue::from_str(&http_addr.authority()) {
+                headers.insert(header::HOST, host);
+            }
+
+            let mut mod_req = Request::new(body);
+            *mod_req.method_mut() = parts.method;
+            *mod_req.headers_mut() = headers;
+            *mod_req.uri_mut() = path.parse().map_err(|e| {
+                ProxyError::BadResponse(format!("invalid path: {e}"))
+            })?;
+
+            let send = async {
+                let mut sender =
+                    tunnel::connect(http_addr, state.connect_timeout).await?;
+                sender
+                    .send_request(mod_req)
+                    .await
+                    .map_err(|e| ProxyError::BadResponse(e.to_string()))
+            };
+
+            await_response(send, uploaded, timer).await?.map(Body::new)
+        }
+    };
+
+    // the body is streamed to the client as it arrives from the module
+    let mut resp = Response::builder().status(mod_resp.status());
+
+    if let Some(headers) = resp.headers_mut() {
+        *headers = end_to_end_headers(mod_resp.headers());
+
+        // event streams must reach the client as they are written, so ask
+        // any proxy in front of Slot not to buffer them either
+        if is_event_stream(headers) {
+            headers.insert(
+                HeaderName::from_static("x-accel-buffering"),
+                HeaderValue::from_static("no"),
+            );
+        }
+    }
+
+    let body =
+        with_timeouts(mod_resp.into_body().into_data_stream(), timer, modname);
+
+    resp.body(Body::from_stream(body))
+        .map_err(|e| ProxyError::BadResponse(e.to_string()))
+}
+
+/// Headers describing the connection Slot actually received, which replace
+/// any forwarding headers sent by the client so that modules can't be misled
+/// about who they are talking to. `prefix` is the path prefix the client used
+/// to reach the module, if any. `client_cert` is passed on if given
+fn forwarding_headers(
+    req: &Request,
+    prefix: Option<&str>,
+    client_cert: Option<&ClientCert>,
+) -> HeaderMap {
+    let client = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    let host = request_authority(req);
+
+    // the listener records whether the connection used TLS
+    let proto = req
+        .extensions()
+        .get::<Scheme>()
+        .cloned()
+        .unwrap_or(Scheme::HTTPS);
+
+    let mut headers = HeaderMap::new();
+    let mut forwarded_elems = Vec::with_capacity(3);
+
+    if let Some(ip) = client {
+        // an IP address is always a valid header value
+        let ip_value = HeaderValue::from_str(&ip.to_string()).unwrap();
+        headers.insert(forwarded::REAL_IP, ip_value.clone());
+        headers.insert(forwarded::FORWARDED_FOR, ip_value);
+
+        // IPv6 addresses must be quoted and bracketed (RFC 7239 section 6)
+        forwarded_elems.push(match ip {
+            IpAddr::V4(v4) => format!("for={v4}"),
+            IpAddr::V6(v6) => format!("for=\"[{v6}]\""),
+        });
+    }
+
+    // a scheme is always a valid header value
+    let proto_value = HeaderValue::from_str(proto.as_str()).unwrap();
+    headers.insert(forwarded::FORWARDED_PROTO, proto_value);
+    forwarded_elems.push(format!("proto={proto}"));
+
+    if let Some(host) = host {
+        if let Ok(v) = HeaderValue::from_str(&host) {
+            headers.insert(forwarded::FORWARDED_HOST, v);
+            forwarded_elems.push(format!("host={}", quoted_string(&host)));
+        }
+    }
+
+    if let Ok(v) = HeaderValue::from_str(&forwarded_elems.join(";")) {
+        headers.insert(forwarded::FORWARDED, v);
+    }
+
+    if let Some(v) = prefix.and_then(|p| HeaderValue::from_str(p).ok()) {
+        headers.insert(forwarded::FORWARDED_PREFIX, v);
+    }
+
+    if let Some(ClientCert(subject)) = client_cert {
+        if let Ok(v) = HeaderValue::from_str(subject) {
+            headers.insert(forwarded::CLIENT_CERT_SUBJECT, v);
+        }
+    }
+
+    headers
+}
+
+/// Replace the forwarding headers in `headers` with `forwarding`. Done after
+/// the hop-by-hop filter, which would drop them if the client listed them in
+/// its `Connection` header
+pub fn set_forwarding_headers(headers: &mut HeaderMap, forwarding: HeaderMap) {
+    for name in forwarded::ALL {
+        headers.remove(name);
+    }
+
+    headers.extend(forwarding);
+}
+
+/// `value` as an HTTP quoted-string (RFC 9110 section 5.6.4)
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The host and port the client asked for
+fn request_authority(req: &Request) -> Option<String> {
+    // HTTP/2 requests carry the host in the URI instead of a header
+    req.headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_owned)
+        .or_else(|| req.uri().authority().map(|a| a.to_string()))
+}
+
+/// The hostname the client asked for, without a port. Falls back to the name
+/// sent in the TLS handshake when the request itself doesn't have one
+fn request_host(req: &Request) -> Option<String> {
+    let from_request = request_authority(req)
+        .and_then(|a| a.parse::<Authority>().ok())
+        .map(|a| a.host().to_owned());
+
+    from_request.or_else(|| {
+        req.extensions()
+            .get::<ServerName>()
+            .map(|ServerName(name)| name.clone())
+    })
+}
+
+/// The server name (SNI) the client sent during the TLS handshake
+#[derive(Debug, Clone)]
+pub struct ServerName(pub String);
+
+/// The subject of the certificate the client sent during the TLS handshake,
+/// after it was verified against the client CAs
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub String);
+
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("text/event-stream"))
+}
+
+/// Tracks how much longer a module may take to respond
+#[derive(Clone, Copy)]
+struct ResponseTimer {
+    idle: Duration,
+    deadline: Option<Instant>,
+}
+
+impl ResponseTimer {
+    fn start(timeouts: Timeouts) -> Self {
+        Self {
+            idle: timeouts.idle,
+            deadline: timeouts.total.map(|t| Instant::now() + t),
+        }
+    }
+
+    /// Completes once the module has run past its deadline, if it has one
+    async fn expired(&self) {
+        match self.deadline {
+            Some(d) => tokio::time::sleep_until(d).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Time the module has to make its next bit of progress
+    fn next_wait(&self) -> Duration {
+        match self.deadline {
+            Some(d) => {
+                self.idle.min(d.saturating_duration_since(Instant::now()))
+            }
+            None => self.idle,
+        }
+    }
+}
+
+/// Pass on the client's request body chunk by chunk, ending it with an error
+/// if the client stalls. The receiver completes once the body has been passed
+/// on in full, or has failed
+fn timed_upload(
+    body: Body,
+    timer: ResponseTimer,
+    modname: String,
+) -> (Body, oneshot::Receiver<()>) {
+    let (done, uploaded) = oneshot::channel();
+
+    if body.is_end_stream() {
+        let _ = done.send(());
+        return (body, uploaded);
+    }
+
+    let chunks = body.into_data_stream();
+    let stream = futures::stream::unfold(Some((chunks, done)), move |upload| {
+        let modname = modname.clone();
+        async move {
+            let (mut chunks, done) = upload?;
+
+            match tokio::time::timeout(timer.idle, chunks.next()).await {
+                Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some((chunks, done)))),
+                Ok(Some(Err(e))) => Some((Err(BoxError::from(e)), None)),
+                Ok(None) => {
+                    let _ = done.send(());
+                    None
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Client stalled while sending a request body to \
+                             module \"{modname}\""
+                    );
+                    Some((Err(e.into()), None))
+                }
+            }
+        }
+    });
+
+    (Body::from_stream(stream), uploaded)
+}
+
+/// Wait for the module's response headers. The upload is timed chunk by chunk,
+/// so the module's idle timeout only starts once the whole request body has
+/// been passed on
+async fn await_response<T>(
+    send: impl Future<Output = Result<T, ProxyError>>,
+    uploaded: oneshot::Receiver<()>,
+    timer: ResponseTimer,
+) -> Result<T, ProxyError> {
+    tokio::pin!(send);
+
+    // the module may well answer before reading the whole body
+    tokio::select! {
+        resp = &mut send => return resp,
+        _ = timer.expired() => return Err(ProxyError::Timeout),
+        _ = uploaded => {}
+    }
+
+    tokio::time::timeout(timer.next_wait(), send)
+        .await
+        .map_err(|_| ProxyError::Timeout)?
+}
+
+/// Pass on the module's response body chunk by chunk, ending it with an error
+/// if the module stalls or runs past its deadline
+fn with_timeouts(
+    body: impl Stream<Item = Result<Bytes, axum::Error>> + Send + 'static,
+    timer: ResponseTimer,
+    modname: String,
+) -> impl Stream<Item = Result<Bytes, BoxError>> {
+    futures::stream::unfold(Some(Box::pin(body)), move |body| {
+        let modname = modname.clone();
+        async move {
+            let mut body = body?;
+
+            match tokio::time::timeout(timer.next_wait(), body.next()).await {
+                Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(body))),
+                Ok(Some(Err(e))) => {
+                    log::warn!(
+                        "Error reading response body from module \
+                         \"{modname}\": \"{e}\""
+                    );
+                    Some((Err(e.into()), None))
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    log::warn!(
+                        "Module \"{modname}\" timed out while sending a \
+                         response body"
+                    );
+                    Some((Err(e.into()), None))
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use axum::{http::Method, routing::get, Router};
+    use slot_client::protocol::JoinOptions;
+
+    use super::*;
+    use crate::{
+        config::{Config, ModuleConfig},
+        store::ModuleStore,
+        test_util,
+    };
+
+    /// The request target a module joined with `path` receives when `uri` is
+    /// requested from Slot
+    async fn forwarded_target(path: PathPolicy, uri: &str) -> String {
+        let upstream = test_util::serve(test_util::echo_target()).await;
+        let modules = ModuleStore::new();
+        let options = JoinOptions {
+            path,
+            ..Default::default()
+        };
+        test_util::add_module(
+            &modules,
+            "mod",
+            HttpAddr::Tcp(upstream),
+            options,
+        )
+        .await;
+
+        let slot = test_util::slot(modules, Config::default());
+        let (status, body) = test_util::get(&slot, uri).await;
+        assert_eq!(status, StatusCode::OK);
+        body
+    }
+
+    fn rewrite() -> PathPolicy {
+        PathPolicy::Rewrite("/base".to_owned())
+    }
+
+    #[tokio::test]
+    async fn keep_forwards_the_whole_path() {
+        let target = forwarded_target(PathPolicy::Keep, "/mod/a/b").await;
+        assert_eq!(target, "/mod/a/b");
+    }
+
+    #[tokio::test]
+    async fn strip_removes_the_module_name() {
+        let target = forwarded_target(PathPolicy::Strip, "/mod/a/b").await;
+        assert_eq!(target, "/a/b");
+    }
+
+    #[tokio::test]
+    async fn rewrite_replaces_the_module_name() {
+        assert_eq!(forwarded_target(rewrite(), "/mod/a/b").await, "/base/a/b");
+        assert_eq!(forwarded_target(rewrite(), "/mod/").await, "/base/");
+    }
+
+    #[tokio::test]
+    async fn query_is_passed_through() {
+        let target =
+            forwarded_target(PathPolicy::Strip, "/mod/search?q=a%20b&n=1")
+                .await;
+        assert_eq!(target, "/search?q=a%20b&n=1");
+    }
+
+    #[tokio::test]
+    async fn encoded_characters_stay_encoded() {
+        let target =
+            forwarded_target(PathPolicy::Strip, "/mod/a%3Fb%2Fc%20d?e=f").await;
+        assert_eq!(target, "/a%3Fb%2Fc%20d?e=f");
+    }
+
+    #[tokio::test]
+    async fn dot_segments_cannot_escape_the_base() {
+        let target = forwarded_target(rewrite(), "/mod/../../admin").await;
+        assert_eq!(target, "/base/admin");
+
+        let target = forwarded_target(rewrite(), "/mod/a/%2E%2e/%2e/b").await;
+        assert_eq!(target, "/base/b");
+
+        let target = forwarded_target(PathPolicy::Keep, "/mod/../x").await;
+        assert_eq!(target, "/mod/x");
+    }
+
+    #[tokio::test]
+    async fn encoded_path_reaches_unix_socket_module() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("http.sock");
+        test_util::serve_unix(test_util::echo_target(), &socket);
+
+        let modules = ModuleStore::new();
+        let options = JoinOptions {
+            path: PathPolicy::Strip,
+            ..Default::default()
+        };
+        let uid = std::fs::metadata(&socket).unwrap().uid();
+        let http_addr = HttpAddr::Unix { path: socket, uid };
+        test_util::add_module(&modules, "mod", http_addr, options).await;
+
+        let slot = test_util::slot(modules, Config::default());
+        let (status, body) = test_util::get(&slot, "/mod/a%20b?c=%3F").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "/a%20b?c=%3F");
+    }
+
+    /// Slot with a module which answers with the length of the request body
+    /// after `delay`, and may stay idle for a second
+    async fn counting_module(delay: Duration) -> Router {
+        let module = Router::new().fallback(async move |body: Bytes| {
+            tokio::time::sleep(delay).await;
+            body.len().to_string()
+        });
+        let upstream = test_util::serve(module).await;
+
+        let modules = ModuleStore::new();
+        test_util::add_module(
+            &modules,
+            "mod",
+            HttpAddr::Tcp(upstream),
+            JoinOptions::default(),
+        )
+        .await;
+
+        let mut config = Config::default();
+        let module = ModuleConfig {
+            idle_timeout: Some(1),
+            ..Default::default()
+        };
+        config.modules.insert("mod".to_owned(), module);
+        test_util::slot(modules, config)
+    }
+
+    /// A request body sent as `chunks`, each after a pause of `pause`
+    fn slow_body(chunks: &'static [&'static str], pause: Duration) -> Body {
+        Body::from_stream(futures::stream::iter(chunks).then(
+            move |chunk| async move {
+                tokio::time::sleep(pause).await;
+                Ok::<_, std::io::Error>(*chunk)
+            },
+        ))
+    }
+
+    fn post(body: Body) -> Request {
+        Request::post("/mod/").body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_upload_is_timed_per_chunk() {
+        let slot = counting_module(Duration::ZERO).await;
+        let body = slow_body(&["ab", "cd", "ef"], Duration::from_millis(600));
+
+        let resp = test_util::send(&slot, post(body)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test_util::body_text(resp).await, "6");
+    }
+
+    #[tokio::test]
+    async fn stalled_upload_is_cut_off() {
+        let slot = counting_module(Duration::ZERO).await;
+        let body = slow_body(&["ab", "cd"], Duration::from_secs(3));
+
+        let resp = test_util::send(&slot, post(body)).await;
+        assert_ne!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn response_wait_starts_after_upload() {
+        let slot = counting_module(Duration::from_secs(2)).await;
+        let body = slow_body(&["ab"], Duration::ZERO);
+
+        let resp = test_util::send(&slot, post(body)).await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    /// Slot with modules "blog", which claims blog.example and
+    /// *.wild.example, and "news", which claims nothing. Both answer with
+    /// their name and the request target they received
+    async fn host_routed_slot() -> Router {
+        let modules = ModuleStore::new();
+        for (name, hosts) in [
+            ("blog", vec!["blog.example", "*.wild.example"]),
+            ("news", vec![]),
+        ] {
+            let module = Router::new().fallback(async move |req: Request| {
+                format!("{name} {}", req.uri())
+            });
+            let upstream = test_util::serve(module).await;
+            let options = JoinOptions {
+                hosts: hosts.into_iter().map(str::to_owned).collect(),
+                ..Default::default()
+            };
+            test_util::add_module(
+                &modules,
+                name,
+                HttpAddr::Tcp(upstream),
+                options,
+            )
+            .await;
+        }
+        test_util::slot(modules, Config::default())
+    }
+
+    /// What answers a request for `uri` with `host` in its Host header
+    async fn answered_by(slot: &Router, host: &str, uri: &str) -> String {
+        let req = Request::get(uri)
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        let resp = test_util::send(slot, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        test_util::body_text(resp).await
+    }
+
+    #[tokio::test]
+    async fn claimed_hosts_reach_their_module_unchanged() {
+        let slot = host_routed_slot().await;
+
+        for (host, uri) in [
+            ("blog.example", "/posts/1?page=2"),
+            ("blog.example:8443", "/"),
+            ("a.wild.example", "/feed"),
+            ("a.b.wild.example", "/feed"),
+            // the path of another module doesn't matter on a claimed host
+            ("blog.example", "/news/today"),
+        ] {
+            assert_eq!(
+                answered_by(&slot, host, uri).await,
+                format!("blog {uri}")
+            );
+        }
+
+        // HTTP/2 requests carry the host in the target instead
+        let req = Request::get("https://blog.example/posts")
+            .body(Body::empty())
+            .unwrap();
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(test_util::body_text(resp).await, "blog /posts");
+    }
+
+    #[tokio::test]
+    async fn unclaimed_hosts_are_routed_by_path() {
+        let slot = host_routed_slot().await;
+
+        for host in ["news.example", "wild.example", "blog.example.org"] {
+            let answer = answered_by(&slot, host, "/news/today").await;
+            assert!(answer.starts_with("news "), "{host}: {answer}");
+
+            let answer = answered_by(&slot, host, "/blog/posts").await;
+            assert!(answer.starts_with("blog "), "{host}: {answer}");
+        }
+    }
+
+    /// Slot with `module` registered as "mod", configured with `config`
+    async fn configured_slot(module: Router, config: ModuleConfig) -> Router {
+        let mut slot_config = Config::default();
+        slot_config.modules.insert("mod".to_owned(), config);
+        test_util::slot_with_module(module, JoinOptions::default(), slot_config)
+            .await
+    }
+
+    /// A module answering with an event stream which never ends, sending an
+    /// event straight away and then one every `interval`
+    fn event_module(interval: Duration) -> Router {
+        Router::new().fallback(async move || {
+            let events = futures::stream::unfold(0, move |n| async move {
+                if n > 0 {
+                    tokio::time::sleep(interval).await;
+                }
+                let event = format!("data: {n}\n\n");
+                Some((Ok::<_, std::io::Error>(event), n + 1))
+            });
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(events),
+            )
+        })
+    }
+
+    /// The next chunk of `body`, failing the test if it takes too long
+    async fn next_chunk(
+        body: &mut (impl Stream<Item = Result<Bytes, axum::Error>> + Unpin),
+    ) -> Option<Result<Bytes, axum::Error>> {
+        tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("no chunk within 5 seconds")
+    }
+
+    #[tokio::test]
+    async fn events_reach_the_client_as_they_are_sent() {
+        let module = event_module(Duration::from_millis(100));
+        let slot = configured_slot(module, ModuleConfig::default()).await;
+
+        let req = Request::get("/mod/events").body(Body::empty()).unwrap();
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-accel-buffering"], "no");
+
+        // the module never finishes, so these can only arrive if each event
+        // is passed on as it comes
+        let mut body = resp.into_body().into_data_stream();
+        for n in 0..3 {
+            let chunk = next_chunk(&mut body).await.unwrap().unwrap();
+            assert_eq!(chunk, format!("data: {n}\n\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_response_body_is_cut_off() {
+        let module = event_module(Duration::from_secs(3600));
+        let config = ModuleConfig {
+            idle_timeout: Some(1),
+            ..Default::default()
+        };
+        let slot = configured_slot(module, config).await;
+
+        let req = Request::get("/mod/events").body(Body::empty()).unwrap();
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut body = resp.into_body().into_data_stream();
+        let chunk = next_chunk(&mut body).await.unwrap().unwrap();
+        assert_eq!(chunk, "data: 0\n\n");
+        assert!(next_chunk(&mut body).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn total_timeout_ends_a_long_response() {
+        let module = event_module(Duration::from_millis(200));
+        let config = ModuleConfig {
+            idle_timeout: Some(5),
+            total_timeout: Some(1),
+            ..Default::default()
+        };
+        let slot = configured_slot(module, config).await;
+
+        let started = Instant::now();
+        let req = Request::get("/mod/events").body(Body::empty()).unwrap();
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the module is never idle for long, yet the body ends in an error
+        // once the deadline has passed
+        let mut body = resp.into_body().into_data_stream();
+        let mut events = 0;
+        loop {
+            match next_chunk(&mut body).await {
+                Some(Ok(_)) => events += 1,
+                Some(Err(_)) => break,
+                None => panic!("body ended without an error"),
+            }
+        }
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(events >= 2, "only {events} events arrived");
+        assert!(body.next().await.is_none());
+    }
+
+    /// Slot with a module using `client_auth`, which answers with the
+    /// forwarding headers it received
+    async fn forwarding_headers_module(client_auth: ClientAuth) -> Router {
+        let module = Router::new().fallback(async |headers: HeaderMap| {
+            let mut received: Vec<String> = forwarded::ALL
+                .iter()
+                .flat_map(|name| {
+                    headers.get_all(*name).iter().map(move |value| {
+                        format!("{name}: {}", value.to_str().unwrap())
+                    })
+                })
+                .collect();
+            received.sort();
+            received.join("\n")
+        });
+        let upstream = test_util::serve(module).await;
+
+        let modules = ModuleStore::new();
+        test_util::add_module(
+            &modules,
+            "mod",
+            HttpAddr::Tcp(upstream),
+            JoinOptions::default(),
+        )
+        .await;
+
+        let mut config = Config::default();
+        let module = ModuleConfig {
+            client_auth,
+            ..Default::default()
+        };
+        config.modules.insert("mod".to_owned(), module);
+        test_util::slot(modules, config)
+    }
+
+    /// The forwarding headers a module receives for `req`
+    async fn received_forwarding_headers(req: Request) -> String {
+        let slot = forwarding_headers_module(ClientAuth::Off).await;
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        test_util::body_text(resp).await
+    }
+
+    /// What a module using `client_auth` receives from a client which
+    /// authenticated as `subject`, and sent a client certificate header of its
+    /// own. Slot's status if it refused the request
+    async fn received_client_cert(
+        client_auth: ClientAuth,
+        subject: Option<&str>,
+    ) -> Result<String, StatusCode> {
+        let slot = forwarding_headers_module(client_auth).await;
+
+        let mut req = Request::get("/mod/")
+            .header(forwarded::CLIENT_CERT_SUBJECT, "CN=spoofed")
+            .body(Body::empty())
+            .unwrap();
+        if let Some(subject) = subject {
+            req.extensions_mut().insert(ClientCert(subject.to_owned()));
+        }
+
+        let resp = test_util::send(&slot, req).await;
+        if resp.status() != StatusCode::OK {
+            return Err(resp.status());
+        }
+        let received = test_util::body_text(resp).await;
+        Ok(received
+            .lines()
+            .filter(|l| l.starts_with(forwarded::CLIENT_CERT_SUBJECT))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn client_cert_subject_is_passed_on() {
+        let expected = Ok("x-client-cert-subject: CN=laptop".to_owned());
+
+        let received =
+            received_client_cert(ClientAuth::Required, Some("CN=laptop"));
+        assert_eq!(received.await, expected);
+
+        let received =
+            received_client_cert(ClientAuth::Optional, Some("CN=laptop"));
+        assert_eq!(received.await, expected);
+    }
+
+    #[tokio::test]
+    async fn client_cert_is_required() {
+        let received = received_client_cert(ClientAuth::Required, None).await;
+        assert_eq!(received, Err(StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn spoofed_client_cert_header_is_removed() {
+        let received = received_client_cert(ClientAuth::Optional, None).await;
+        assert_eq!(received, Ok(String::new()));
+
+        // modules not using client certificates never get the header
+        let received =
+            received_client_cert(ClientAuth::Off, Some("CN=laptop")).await;
+        assert_eq!(received, Ok(String::new()));
+    }
+
+    #[tokio::test]
+    async fn forwarding_headers_replace_the_clients() {
+        let req = Request::get("/mod/")
+            .header(header::HOST, "example.com:8443")
+            .header(forwarded::FORWARDED_PROTO, "gopher")
+            .header(forwarded::CLIENT_CERT_SUBJECT, "CN=admin")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(
+            received_forwarding_headers(req).await,
+            "forwarded: proto=https;host=\"example.com:8443\"\n\
+             x-forwarded-host: example.com:8443\n\
+             x-forwarded-prefix: /mod\n\
+             x-forwarded-proto: https"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_addresses_are_replaced_not_appended_to() {
+        let mut req = Request::get("/mod/")
+            .header(header::HOST, "a.example")
+            .header(forwarded::FORWARDED, "for=10.0.0.1")
+            .header(forwarded::FORWARDED, "for=10.0.0.2")
+            .header(forwarded::FORWARDED_FOR, "10.0.0.1, 10.0.0.2")
+            .header(forwarded::FORWARDED_HOST, "evil.example")
+            .header(forwarded::FORWARDED_PREFIX, "/admin")
+            .header(forwarded::REAL_IP, "10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        let client: SocketAddr = "[2001:db8::7]:5555".parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(client));
+
+        assert_eq!(
+            received_forwarding_headers(req).await,
+            "forwarded: for=\"[2001:db8::7]\";proto=https;host=\"a.example\"\n\
+             x-forwarded-for: 2001:db8::7\n\
+             x-forwarded-host: a.example\n\
+             x-forwarded-prefix: /mod\n\
+             x-forwarded-proto: https\n\
+             x-real-ip: 2001:db8::7"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwarding_headers_survive_connection_listing() {
+        let req = Request::get("/mod/")
+            .header(header::HOST, "example.com")
+            .header(
+                header::CONNECTION,
+                "x-forwarded-proto, forwarded, x-forwarded-host",
+            )
+            .header(forwarded::FORWARDED_PROTO, "gopher")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(
+            received_forwarding_headers(req).await,
+            "forwarded: proto=https;host=\"example.com\"\n\
+             x-forwarded-host: example.com\n\
+             x-forwarded-prefix: /mod\n\
+             x-forwarded-proto: https"
+        );
+    }
+
+    /// Slot with `module` registered as "mod"
+    async fn slot_with(module: Router) -> Router {
+        test_util::slot_with_module(
+            module,
+            JoinOptions::default(),
+            Config::default(),
+        )
+        .await
+    }
+
+    /// Slot with a module which answers with the request body, the method in
+    /// "echo-method" and each request header as "echo-{name}". Its response
+    /// also carries hop-by-hop headers of its own
+    async fn echo_module() -> Router {
+        let module = Router::new().fallback(async |req: Request| {
+            let (parts, body) = req.into_parts();
+            let mut resp = Response::new(body);
+            let headers = resp.headers_mut();
+
+            for (name, value) in &parts.headers {
+                let name = format!("echo-{name}");
+                headers
+                    .append(HeaderName::try_from(name).unwrap(), value.clone());
+            }
+            headers.insert(
+                "echo-method",
+                HeaderValue::from_str(parts.method.as_str()).unwrap(),
+            );
+
+            headers.insert("x-module", HeaderValue::from_static("echo"));
+            headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+            headers.insert("x-module-hop", HeaderValue::from_static("1"));
+            headers.insert(header::CONNECTION, "x-module-hop".parse().unwrap());
+            resp
+        });
+        slot_with(module).await
+    }
+
+    #[tokio::test]
+    async fn post_body_reaches_the_module() {
+        let slot = echo_module().await;
+        let json = r#"{"name":"slot","tags":["a","b"],"n":1.5}"#;
+        let req = Request::post("/mod/api?x=1")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, json.len())
+            .body(Body::from(json))
+            .unwrap();
+
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["echo-method"], "POST");
+        assert_eq!(resp.headers()["echo-content-type"], "application/json");
+        assert_eq!(
+            resp.headers()["echo-content-length"],
+            json.len().to_string()
+        );
+        assert_eq!(test_util::body_text(resp).await, json);
+    }
+
+    #[tokio::test]
+    async fn methods_reach_the_module() {
+        let slot = echo_module().await;
+        for method in [Method::PUT, Method::DELETE, Method::PATCH] {
+            let req = Request::builder()
+                .method(method.clone())
+                .uri("/mod/")
+                .body(Body::from("body"))
+                .unwrap();
+
+            let resp = test_util::send(&slot, req).await;
+            assert_eq!(resp.headers()["echo-method"], method.as_str());
+            assert_eq!(test_util::body_text(resp).await, "body");
+        }
+    }
+
+    #[tokio::test]
+    async fn end_to_end_headers_pass_through() {
+        let slot = echo_module().await;
+        let req = Request::get("/mod/")
+            .header(header::ACCEPT, "text/html")
+            .header(header::AUTHORIZATION, "Bearer token")
+            .header(header::COOKIE, "a=1")
+            .header("x-custom", "one")
+            .header("x-custom", "two")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = test_util::send(&slot, req).await;
+        let headers = resp.headers();
+        assert_eq!(headers["echo-accept"], "text/html");
+        assert_eq!(headers["echo-authorization"], "Bearer token");
+        assert_eq!(headers["echo-cookie"], "a=1");
+        let custom: Vec<_> = headers.get_all("echo-x-custom").iter().collect();
+        assert_eq!(custom, ["one", "two"]);
+
+        assert_eq!(headers["x-module"], "echo");
+    }
+
+    #[tokio::test]
+    async fn hop_by_hop_request_headers_are_stripped() {
+        let slot = echo_module().await;
+        let req = Request::post("/mod/")
+            .header(header::CONNECTION, "keep-alive, x-hop")
+            .header("keep-alive", "timeout=5")
+            .header(header::TE, "trailers")
+            .header(header::TRANSFER_ENCODING, "gzip, chunked")
+            .header(header::UPGRADE, "websocket")
+            .header("x-hop", "1")
+            .header("x-end-to-end", "1")
+            .body(Body::from("body"))
+            .unwrap();
+
+        let resp = test_util::send(&slot, req).await;
+        let headers = resp.headers();
+        for name in [
+            "echo-connection",
+            "echo-keep-alive",
+            "echo-te",
+            "echo-upgrade",
+            "echo-x-hop",
+        ] {
+            assert!(!headers.contains_key(name), "{name} was forwarded");
+        }
+        assert_eq!(headers["echo-x-end-to-end"], "1");
+
+        // the body is chunked again on the way to the module, but never with
+        // the client's codings
+        let transfer_encoding = headers.get("echo-transfer-encoding");
+        assert!(transfer_encoding.is_none_or(|v| v == "chunked"));
+
+        assert_eq!(test_util::body_text(resp).await, "body");
+    }
+
+    #[tokio::test]
+    async fn hop_by_hop_response_headers_are_stripped() {
+        let slot = echo_module().await;
+        let resp = test_util::send(
+            &slot,
+            Request::get("/mod/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        let headers = resp.headers();
+        assert!(!headers.contains_key(header::CONNECTION));
+        assert!(!headers.contains_key("keep-alive"));
+        assert!(!headers.contains_key("x-module-hop"));
+        assert_eq!(headers["x-module"], "echo");
+    }
+
+    #[test]
+    fn end_to_end_headers_drops_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::CONNECTION,
+            "Keep-Alive, X-Listed".parse().unwrap(),
+        );
+        headers.append(header::CONNECTION, "x-also-listed".parse().unwrap());
+        for name in [
+            "keep-alive",
+            "proxy-connection",
+            "te",
+            "trailer",
+            "transfer-encoding",
+            "upgrade",
+            "proxy-authorization",
+            "proxy-authenticate",
+            "x-listed",
+            "x-also-listed",
+        ] {
+            headers.insert(name, HeaderValue::from_static("1"));
+        }
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        headers.append("x-kept", "a".parse().unwrap());
+        headers.append("x-kept", "b".parse().unwrap());
+
+        let kept = end_to_end_headers(&headers);
+        let names: Vec<_> = kept.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, ["content-type", "x-kept"]);
+        assert_eq!(kept.get_all("x-kept").iter().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn statuses_pass_through() {
+        let module = Router::new()
+            .route(
+                "/mod/missing",
+                get(async || (StatusCode::NOT_FOUND, "module's own 404")),
+            )
+            .route(
+                "/mod/teapot",
+                get(async || (StatusCode::IM_A_TEAPOT, "short and stout")),
+            );
+        let slot = slot_with(module).await;
+
+        let (status, body) = test_util::get(&slot, "/mod/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "module's own 404");
+
+        let (status, body) = test_util::get(&slot, "/mod/teapot").await;
+        assert_eq!(status, StatusCode::IM_A_TEAPOT);
+        assert_eq!(body, "short and stout");
+    }
+
+    #[tokio::test]
+    async fn partial_content_passes_through() {
+        let module = Router::new().fallback(async |headers: HeaderMap| {
+            assert_eq!(headers[header::RANGE], "bytes=2-5");
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (header::CONTENT_RANGE, "bytes 2-5/10"),
+                    (header::ACCEPT_RANGES, "bytes"),
+                ],
+                "2345",
+            )
+        });
+        let slot = slot_with(module).await;
+
+        let req = Request::get("/mod/file")
+            .header(header::RANGE, "bytes=2-5")
+            .body(Body::empty())
+            .unwrap();
+        let resp = test_util::send(&slot, req).await;
+
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "4");
+        assert_eq!(test_util::body_text(resp).await, "2345");
+    }
+
+    #[tokio::test]
+    async fn large_streamed_body_passes_through() {
+        const CHUNK: usize = 64 * 1024;
+        const CHUNKS: usize = 128;
+
+        // each chunk is filled with its own index, so reordered or lost
+        // chunks show
+        let module = Router::new().fallback(async || {
+            let chunks = futures::stream::iter(0..CHUNKS)
+                .map(|i| Ok::<_, std::io::Error>(vec![i as u8; CHUNK]));
+            Body::from_stream(chunks)
+        });
+        let slot = slot_with(module).await;
+
+        let resp = test_util::send(
+            &slot,
+            Request::get("/mod/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body.len(), CHUNK * CHUNKS);
+        for (i, chunk) in body.chunks(CHUNK).enumerate() {
+            assert!(chunk.iter().all(|b| *b == i as u8), "chunk {i}");
+        }
+    }
+
+    #[tokio::test]
+    async fn module_dropping_mid_body_aborts_the_response() {
+        // a module which promises more body than it sends
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let (mut cnx, _) = listener.accept().await.unwrap();
+            let mut req = [0; 1024];
+            let _ = cnx.read(&mut req).await.unwrap();
+            cnx.write_all(
+                b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\npartial",
+            )
+            .await
+            .unwrap();
+        });
+
+        let modules = ModuleStore::new();
+        test_util::add_module(
+            &modules,
+            "mod",
+            HttpAddr::Tcp(upstream),
+            JoinOptions::default(),
+        )
+        .await;
+        let slot = test_util::slot(modules, Config::default());
+
+        let resp = test_util::send(
+            &slot,
+            Request::get("/mod/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "100");
+
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await;
+        assert!(body.is_err());
+    }
+
+    /// Slot with the echo target module joined with `root`
+    async fn root_module(root: RootPolicy) -> Router {
+        let options = JoinOptions {
+            root,
+            ..Default::default()
+        };
+        test_util::slot_with_module(
+            test_util::echo_target(),
+            options,
+            Config::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn bare_root_redirects_keeping_the_query() {
+        let slot = root_module(RootPolicy::Redirect).await;
+
+        for (uri, location) in [("/mod", "/mod/"), ("/mod?x=1", "/mod/?x=1")] {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            let resp = test_util::send(&slot, req).await;
+            assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(resp.headers()[header::LOCATION], location);
+        }
+
+        // the redirect target itself is forwarded
+        let (status, body) = test_util::get(&slot, "/mod/?x=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "/mod/?x=1");
+    }
+
+    #[tokio::test]
+    async fn bare_root_is_forwarded_as_is() {
+        let slot = root_module(RootPolicy::Forward).await;
+
+        let (status, body) = test_util::get(&slot, "/mod").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "/mod");
+
+        let (status, body) = test_util::get(&slot, "/mod?x=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "/mod?x=1");
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_get_slots_404_page() {
+        let slot = root_module(RootPolicy::Redirect).await;
+
+        let (status, body) = test_util::get(&slot, "/nope/page").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("Module &quot;nope&quot; does not exist"));
+
+        let (status, body) = test_util::get(&slot, "/nope").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("Module &quot;nope&quot; does not exist"));
+
+        // a request no route matches falls back to `not_found`
+        let req = Request::options("*").body(Body::empty()).unwrap();
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = test_util::body_text(resp).await;
+        assert!(body.contains("The requested page does not exist"));
+    }
+
+    #[test]
+    fn quoted_string_escapes_quotes_and_backslashes() {
+        assert_eq!(quoted_string("example.com"), "\"example.com\"");
+        assert_eq!(quoted_string(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+
+    #[test]
+    fn raw_rest_resolves_dot_segments() {
+        assert_eq!(raw_rest("/mod/a/b/"), "a/b/");
+        assert_eq!(raw_rest("/mod/"), "");
+        assert_eq!(raw_rest("/mod"), "");
+        assert_eq!(raw_rest("/mod/a/../b"), "b");
+        assert_eq!(raw_rest("/mod/a/.."), "");
+        assert_eq!(raw_rest("/mod/a/b/.."), "a/");
+    }
+}
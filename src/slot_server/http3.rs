@@ -0,0 +1,240 @@
+//! HTTP/3 over QUIC, built with the "http3" cargo feature
+//!
+//! The QUIC listener uses the HTTPS port over UDP, shares the certificates of
+//! the TCP listener and serves the same routes. Browsers only try HTTP/3 after
+//! seeing the Alt-Svc header, which is added to responses sent over TCP.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{header::ALT_SVC, uri::Scheme, Extensions, HeaderValue},
+    response::Response,
+    Router,
+};
+use bytes::{Buf, Bytes};
+use futures::StreamExt;
+use h3::server::RequestResolver;
+use tokio_rustls::rustls::{pki_types::CertificateDer, ServerConfig};
+use tower::ServiceExt;
+
+use crate::{forward, listener, tls};
+
+/// The ALPN protocol of HTTP/3
+const H3_ALPN: &[u8] = b"h3";
+
+/// Seconds clients may remember that HTTP/3 is available
+const ALT_SVC_MAX_AGE: u32 = 86400;
+
+/// Tell clients that HTTP/3 is available on `port`
+pub fn advertise(routes: Router, port: u16) -> Router {
+    let alt_svc =
+        HeaderValue::from_str(&format!("h3=\":{port}\"; ma={ALT_SVC_MAX_AGE}"))
+            .expect("The Alt-Svc value is always valid");
+
+    routes.layer(axum::middleware::map_response(move |mut resp: Response| {
+        resp.headers_mut().insert(ALT_SVC, alt_svc.clone());
+        async { resp }
+    }))
+}
+
+/// Serve `routes` over HTTP/3 on `addr` using the certificates of `tls`.
+/// QUIC connections and handshakes count towards the same limits as TCP ones
+pub async fn serve(
+    addr: SocketAddr,
+    tls: &ServerConfig,
+    routes: Router,
+    settings: listener::Settings,
+) {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![H3_ALPN.to_vec()];
+
+    let quic_tls = match quinn::crypto::rustls::QuicServerConfig::try_from(
+        Arc::new(tls),
+    ) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Unable to use the TLS config for QUIC: \"{e}\"");
+            return;
+        }
+    };
+
+    let quic_config = quinn::ServerConfig::with_crypto(Arc::new(quic_tls));
+
+    let endpoint = match quinn::Endpoint::server(quic_config, addr) {
+        Ok(e) => e,
+        Err(e) => {
+            log::error!("Unable to bind HTTP/3 listener on {addr}: \"{e}\"");
+            return;
+        }
+    };
+
+    log::info!("HTTP/3 listening on {addr}");
+
+    loop {
+        // connections wait in the endpoint until there is room for them
+        let permit = settings.connection_permit().await;
+
+        let Some(incoming) = endpoint.accept().await else {
+            break;
+        };
+
+        let routes = routes.clone();
+        let settings = settings.clone();
+
+        tokio::spawn(async move {
+            let addr = incoming.remote_address();
+
+            // the same limits as TLS handshakes over TCP
+            let conn = match settings.limit_handshake(incoming).await {
+                Ok(c) => c,
+                Err(reason) => {
+                    log::log!(
+                        settings.handshake_log_level(),
+                        "QUIC handshake with {addr} failed: \"{reason}\""
+                    );
+                    return;
+                }
+            };
+
+            // the same details the TCP listener gives handlers
+            let mut conn_info = Extensions::new();
+            conn_info.insert(Scheme::HTTPS);
+
+            let server_name = conn
+                .handshake_data()
+                .and_then(|d| {
+                    d.downcast::<quinn::crypto::rustls::HandshakeData>().ok()
+                })
+                .and_then(|d| d.server_name);
+            if let Some(name) = server_name {
+                conn_info.insert(forward::ServerName(name));
+            }
+
+            let chain = conn.peer_identity().and_then(|certs| {
+                certs.downcast::<Vec<CertificateDer<'static>>>().ok()
+            });
+            if let Some(cert) =
+                tls::client_cert(chain.as_deref().map(|c| &c[..]))
+            {
+                conn_info.insert(cert);
+            }
+            conn_info.insert(permit);
+
+            let h3_conn = h3::server::Connection::<_, Bytes>::new(
+                h3_quinn::Connection::new(conn),
+            )
+            .await;
+
+            let mut h3_conn = match h3_conn {
+                Ok(c) => c,
+                Err(e) => {
+                    log::warn!("Error serving HTTP/3 to {addr}: {e}");
+                    return;
+                }
+            };
+
+            loop {
+                match h3_conn.accept().await {
+                    Ok(Some(resolver)) => {
+                        let routes = routes.clone();
+                        let conn_info = conn_info.clone();
+                        tokio::spawn(async move {
+                            let served = serve_request(
+                                resolver, routes, addr, conn_info,
+                            )
+                            .await;
+                            if let Err(e) = served {
+                                log::warn!(
+                                    "Error serving HTTP/3 request from {addr}: \
+                                     {e}"
+                                );
+                            }
+                        });
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        if !e.is_h3_no_error() {
+                            log::warn!("Error serving HTTP/3 to {addr}: {e}");
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    routes: Router,
+    addr: SocketAddr,
+    conn_info: Extensions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (req, stream) = resolver.resolve_request().await?;
+    let (mut send, mut recv) = stream.split();
+
+    // look at the first chunk so that bodiless requests are forwarded without
+    // a body, rather than with an empty streamed one
+    let body = match recv.recv_data().await? {
+        None => Body::empty(),
+        Some(mut first) => {
+            let first = first.copy_to_bytes(first.remaining());
+            let rest = futures::stream::unfold(Some(recv), |recv| async move {
+                let mut recv = recv?;
+                match recv.recv_data().await {
+                    Ok(Some(mut data)) => {
+                        let data = data.copy_to_bytes(data.remaining());
+                        Some((Ok(data), Some(recv)))
+                    }
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e), None)),
+                }
+            });
+            Body::from_stream(
+                futures::stream::once(async { Ok(first) }).chain(rest),
+            )
+        }
+    };
+
+    let (parts, ()) = req.into_parts();
+    let mut req = Request::from_parts(parts, body);
+
+    req.extensions_mut().insert(ConnectInfo(addr));
+    req.extensions_mut().extend(conn_info);
+
+    let Ok(resp) = routes.oneshot(req).await;
+    let (parts, body) = resp.into_parts();
+
+    send.send_response(Response::from_parts(parts, ())).await?;
+
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        send.send_data(chunk?).await?;
+    }
+
+    send.finish().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, routing::get};
+
+    use super::*;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn responses_advertise_http3() {
+        let routes = Router::new().route("/", get(async || "ok"));
+        let routes = advertise(routes, 8443);
+
+        for uri in ["/", "/missing"] {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            let resp = test_util::send(&routes, req).await;
+            assert_ne!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(resp.headers()[ALT_SVC], "h3=\":8443\"; ma=86400");
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! The public listeners which serve the routes over HTTP or HTTPS
+
+use std::{
+    fmt::Display, future::IntoFuture, net::SocketAddr, sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{uri::Scheme, Extensions},
+    Router,
+};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use ipnet::IpNet;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::{
+    rustls::{server::Acceptor, ServerConfig},
+    server::TlsStream,
+    LazyConfigAcceptor,
+};
+use tower::Service;
+
+use crate::{
+    acme, cli::Args, config::ProxyProtocolConfig, forward::ServerName,
+    proxy_protocol, tls,
+};
+
+/// First wait before accepting again after accepting failed, e.g., because
+/// the process ran out of file descriptors
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+
+/// Longest wait between failed attempts to accept
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How client connections are accepted, shared by every listener
+#[derive(Debug, Clone)]
+pub struct Settings {
+    connections: Arc<Semaphore>,
+    handshakes: Arc<Semaphore>,
+    handshake_timeout: Duration,
+    handshake_log_level: log::Level,
+
+    /// Load balancers whose connections start with a PROXY protocol header
+    trusted_proxies: Arc<[IpNet]>,
+}
+
+/// One of the `--max-connections` slots, held for as long as a connection or
+/// a tunnel made from it is open. Every request on the connection carries a
+/// handle to it, so a tunnel can keep it after the connection is handed over
+#[derive(Debug, Clone)]
+pub struct ConnectionPermit {
+    _permit: Arc<OwnedSemaphorePermit>,
+}
+
+impl Settings {
+    pub fn new(args: &Args, proxy_protocol: &ProxyProtocolConfig) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(args.max_connections)),
+            handshakes: Arc::new(Semaphore::new(args.max_handshakes)),
+            handshake_timeout: Duration::from_secs(args.handshake_timeout),
+            handshake_log_level: args.handshake_log_level,
+            trusted_proxies: proxy_protocol.trusted.clone().into(),
+        }
+    }
+
+    /// Wait until a connection may be accepted under `--max-connections`
+    pub async fn connection_permit(&self) -> ConnectionPermit {
+        let permit = match self.connections.clone().try_acquire_owned() {
+            Ok(p) => p,
+            Err(_) => {
+                log::warn!(
+                    "Connection limit reached. New connections must wait"
+                );
+                self.connections
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("The connection semaphore is never closed")
+            }
+        };
+
+        ConnectionPermit {
+            _permit: Arc::new(permit),
+        }
+    }
+
+    /// Run a TLS or QUIC handshake under `--max-handshakes` and
+    /// `--handshake-timeout`. Fails with why the handshake didn't complete
+    pub async fn limit_handshake<T, E: Display>(
+        &self,
+        handshake: impl IntoFuture<Output = Result<T, E>>,
+    ) -> Result<T, String> {
+        let _permit = self
+            .handshakes
+            .acquire()
+            .await
+            .expect("The handshake semaphore is never closed");
+
+        match tokio::time::timeout(self.handshake_timeout, handshake).await {
+            Ok(Ok(done)) => Ok(done),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => {
+                Err(format!("timed out after {:?}", self.handshake_timeout))
+            }
+        }
+    }
+
+    /// Level at which failed handshakes are logged
+    pub fn handshake_log_level(&self) -> log::Level {
+        self.handshake_log_level
+    }
+}
+
+/// Serve `routes` over TLS on `addr`
+pub async fn serve_https(
+    addr: SocketAddr,
+    tls_config: Arc<ServerConfig>,
+    routes: Router,
+    settings: Settings,
+) {
+    let tcp_listener = bind(addr).await;
+    log::info!("Webserver listening on {addr}");
+
+    loop {
+        let routes = routes.clone();
+        let tls_config = tls_config.clone();
+        let settings = settings.clone();
+
+        // Wait for new tcp connection
+        let (mut cnx, addr, permit) = accept(&tcp_listener, &settings).await;
+
+        tokio::spawn(async move {
+            let Some(addr) = client_addr(&mut cnx, addr, &settings).await
+            else {
+                return;
+            };
+
+            // Wait for tls handshake to happen
+            let Some(stream) =
+                handshake(cnx, addr, tls_config, &settings).await
+            else {
+                return;
+            };
+
+            // a TLS-ALPN-01 validation is over once the handshake is done
+            if stream.get_ref().1.alpn_protocol() == Some(acme::ACME_TLS_ALPN) {
+                log::debug!("Completed ACME TLS-ALPN-01 handshake with {addr}");
+                return;
+            }
+
+            let tls_conn = stream.get_ref().1;
+
+            let mut conn_info = Extensions::new();
+            conn_info.insert(Scheme::HTTPS);
+            if let Some(name) = tls_conn.server_name() {
+                conn_info.insert(ServerName(name.to_owned()));
+            }
+            if let Some(cert) = tls::client_cert(tls_conn.peer_certificates()) {
+                conn_info.insert(cert);
+            }
+            conn_info.insert(permit);
+
+            serve_connection(stream, addr, conn_info, routes).await;
+        });
+    }
+}
+
+/// Serve `routes` without TLS on `addr`
+pub async fn serve_http(addr: SocketAddr, routes: Router, settings: Settings) {
+    let tcp_listener = bind(addr).await;
+    log::info!("Webserver listening on {addr} without TLS");
+
+    loop {
+        let routes = routes.clone();
+        let settings = settings.clone();
+        let (mut cnx, addr, permit) = accept(&tcp_listener, &settings).await;
+
+        let mut conn_info = Extensions::new();
+        conn_info.insert(Scheme::HTTP);
+        conn_info.insert(permit);
+
+        tokio::spawn(async move {
+            let Some(addr) = client_addr(&mut cnx, addr, &settings).await
+            else {
+                return;
+            };
+
+            serve_connection(cnx, addr, conn_info, routes).await;
+        });
+    }
+}
+
+async fn bind(addr: SocketAddr) -> TcpListener {
+    match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            log::error!("Unable to listen on {addr}: \"{e}\"");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Wait for a connection slot, then for a client to connect. Failing to
+/// accept is retried with a growing delay rather than given up on
+async fn accept(
+    tcp_listener: &TcpListener,
+    settings: &Settings,
+) -> (TcpStream, SocketAddr, ConnectionPermit) {
+    let permit = settings.connection_permit().await;
+
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        match tcp_listener.accept().await {
+            Ok((cnx, addr)) => return (cnx, addr, permit),
+            Err(e) => {
+                log::warn!(
+                    "Failed to accept a connection. Retrying in {backoff:?}: \
+                     \"{e}\""
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+/// The address of the client at the other end of `cnx`. Connections from
+/// trusted load balancers are dropped unless they start with a PROXY protocol
+/// header saying who the client is
+async fn client_addr(
+    cnx: &mut TcpStream,
+    peer: SocketAddr,
+    settings: &Settings,
+) -> Option<SocketAddr> {
+    let peer_ip = peer.ip().to_canonical();
+    if !settings
+        .trusted_proxies
+        .iter()
+        .any(|net| net.contains(&peer_ip))
+    {
+        return Some(peer);
+    }
+
+    let header = tokio::time::timeout(
+        settings.handshake_timeout,
+        proxy_protocol::read_header(cnx),
+    )
+    .await;
+
+    match header {
+        Ok(Ok(Some(client))) => {
+            log::debug!("Connection from {client} forwarded by {peer}");
+            Some(client)
+        }
+        Ok(Ok(None)) => Some(peer),
+        Ok(Err(e)) => {
+            log::warn!("Dropping connection from {peer}: \"{e}\"");
+            None
+        }
+        Err(_) => {
+            log::warn!(
+                "Dropping connection from {peer}: no PROXY protocol header \
+                 after {:?}",
+                settings.handshake_timeout
+            );
+            None
+        }
+    }
+}
+
+/// Complete the TLS handshake with the client at `addr`. Failures are logged
+/// along with what the client asked for
+async fn handshake(
+    cnx: TcpStream,
+    addr: SocketAddr,
+    tls_config: Arc<ServerConfig>,
+    settings: &Settings,
+) -> Option<TlsStream<TcpStream>> {
+    let mut server_name = None;
+    let mut alpn = Vec::new();
+
+    let handshake = async {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), cnx).await?;
+
+        let hello = start.client_hello();
+        server_name = hello.server_name().map(str::to_owned);
+        alpn = hello
+            .alpn()
+            .map(|protos| {
+                protos
+                    .map(|p| String::from_utf8_lossy(p).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        start.into_stream(tls_config).await
+    };
+
+    let reason = match settings.limit_handshake(handshake).await {
+        Ok(stream) => return Some(stream),
+        Err(reason) => reason,
+    };
+
+    log::log!(
+        settings.handshake_log_level(),
+        "TLS handshake with {addr} failed (SNI {}, ALPN {}): \"{reason}\"",
+        server_name.as_deref().unwrap_or("none"),
+        match alpn.is_empty() {
+            true => "none".to_owned(),
+            false => alpn.join(","),
+        },
+    );
+
+    None
+}
+
+/// Serve `routes` on an established connection from `addr`. `conn_info`
+/// describes how the client connected and is added to every request
+async fn serve_connection<I>(
+    stream: I,
+    addr: SocketAddr,
+    conn_info: Extensions,
+    routes: Router,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Hyper has its own `AsyncRead` and `AsyncWrite` traits and doesn't
+    // use tokio. `TokioIo` converts between them.
+    let stream = TokioIo::new(stream);
+
+    // Hyper also has its own `Service` trait and doesn't use tower. We
+    // can use `hyper::service::service_fn` to create a hyper `Service`
+    // that calls our app through `tower::Service::call`.
+    let hyper_service =
+        hyper::service::service_fn(move |mut request: Request<Incoming>| {
+            // Let handlers know who they are talking to
+            request.extensions_mut().insert(ConnectInfo(addr));
+            request.extensions_mut().extend(conn_info.clone());
+
+            // We have to clone `routes` because hyper's `Service` uses
+            // `&self` whereas tower's `Service` requires `&mut self`.
+            // We don't need to call `poll_ready` since `Router` is
+            // always ready.
+            routes.clone().call(request)
+        });
+
+    // HTTP/2 is used when the client negotiated it with ALPN, or sent the
+    // HTTP/2 preface on a plain connection. Upgrades such as WebSockets only
+    // happen over HTTP/1.1
+    let ret =
+        hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+            .serve_connection_with_upgrades(stream, hyper_service)
+            .await;
+
+    if let Err(err) = ret {
+        log::warn!("Error serving connection from {addr}: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::test_util;
+
+    fn settings(extra: &[&str]) -> Settings {
+        let args = test_util::args(extra);
+        Settings::new(&args, &ProxyProtocolConfig::default())
+    }
+
+    #[tokio::test]
+    async fn slow_handshakes_time_out() {
+        let settings = settings(&["--handshake-timeout", "1"]);
+
+        let started = Instant::now();
+        let stalled = std::future::pending::<Result<(), String>>();
+        let err = settings.limit_handshake(stalled).await.unwrap_err();
+
+        assert!(err.starts_with("timed out"), "{err}");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn handshakes_wait_for_a_free_slot() {
+        let settings = settings(&["--max-handshakes", "1"]);
+
+        let (finish, finished) = oneshot::channel::<()>();
+        let first = tokio::spawn({
+            let settings = settings.clone();
+            async move { settings.limit_handshake(finished).await }
+        });
+        tokio::task::yield_now().await;
+
+        let second = settings.limit_handshake(async { Ok::<_, String>(2) });
+        tokio::pin!(second);
+        let waited =
+            tokio::time::timeout(Duration::from_millis(200), &mut second);
+        assert!(waited.await.is_err(), "second handshake didn't wait");
+
+        finish.send(()).unwrap();
+        assert_eq!(first.await.unwrap(), Ok(()));
+        assert_eq!(second.await, Ok(2));
+    }
+}
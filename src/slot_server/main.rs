@@ -1,31 +1,36 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
-    extract::{Path, Request, State},
     response::{Redirect, Response},
     routing::{any, get},
     Router,
 };
-use hyper::body::Incoming;
-use hyper_util::rt::{TokioExecutor, TokioIo};
+use cli::{AcmeChallenge, Args, ServeMode};
 use init::initialize;
 use reqwest::StatusCode;
-use tokio_rustls::{
-    rustls::{
-        crypto::{ring, CryptoProvider},
-        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
-        ServerConfig,
-    },
-    TlsAcceptor,
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider},
+    ServerConfig,
 };
-use tower::Service;
-
-use crate::store::ModuleStore;
 
+mod acme;
+mod auth;
 mod cli;
+mod config;
+mod error;
+mod forward;
+#[cfg(feature = "http3")]
+mod http3;
 mod init;
+mod listener;
 mod module_handler;
+mod proxy_protocol;
+mod state;
 mod store;
+#[cfg(test)]
+mod test_util;
+mod tls;
+mod tunnel;
 mod upgrade;
 
 #[tokio::main]
@@ -36,35 +41,184 @@ async fn main() {
     CryptoProvider::install_default(ring::default_provider())
         .expect("Valid crypto implementation");
 
-    let modules = store::ModuleStore::new();
+    let config = config::Config::load(args.config_file.as_deref());
 
-    let key = PrivateKeyDer::from_pem_file(&args.key_file).unwrap();
+    serve(args, config, store::ModuleStore::new()).await;
+}
 
-    let certs = CertificateDer::pem_file_iter(&args.cert_file)
-        .unwrap()
-        .map(Result::unwrap)
-        .collect();
+/// Accept modules, and serve them to clients over the listeners `args.mode`
+/// asks for
+async fn serve(
+    args: Args,
+    config: config::Config,
+    modules: store::ModuleStore,
+) {
+    let acme_tokens = acme::Http01Tokens::default();
+
+    let rustls_config = match args.mode {
+        ServeMode::Https | ServeMode::Both => {
+            Some(tls_config(&args, &config, &acme_tokens))
+        }
+        ServeMode::Http => {
+            if !args.acme_domains.is_empty() {
+                log::warn!("ACME is only used when serving HTTPS");
+            }
+            None
+        }
+    };
 
-    let mut rustls_config = match ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-    {
-        Ok(c) => c,
+    module_handler::module_listener(modules.clone(), &args, &config).await;
+
+    let listener_settings =
+        listener::Settings::new(&args, &config.proxy_protocol);
+    let redirect_config = config.redirect.clone();
+    let hsts_config = config.hsts.clone();
+
+    let app_state = state::AppState::new(modules, &args, config);
+
+    let routes = router(app_state, args.default_redirect.clone());
+
+    let http_addr = SocketAddr::new(args.web_addr, args.http_port);
+    let https_addr = SocketAddr::new(args.web_addr, args.https_port);
+
+    match args.mode {
+        ServeMode::Https => {
+            tokio::spawn(upgrade::redirect_http_to_https(
+                args.clone(),
+                redirect_config,
+                acme_tokens,
+                routes.clone(),
+                listener_settings.clone(),
+            ));
+        }
+        ServeMode::Both => {
+            // challenges must be answered even though the port serves content
+            let http_routes =
+                acme::challenge_routes(acme_tokens).merge(routes.clone());
+            tokio::spawn(listener::serve_http(
+                http_addr,
+                http_routes,
+                listener_settings.clone(),
+            ));
+        }
+        ServeMode::Http => {
+            listener::serve_http(http_addr, routes, listener_settings).await;
+            return;
+        }
+    }
+
+    let Some(rustls_config) = rustls_config else {
+        unreachable!("TLS is configured whenever HTTPS is served");
+    };
+
+    let routes = upgrade::strict_transport_security(routes, &hsts_config);
+
+    #[cfg(feature = "http3")]
+    let routes = {
+        let quic_routes = routes.clone();
+        let rustls_config = rustls_config.clone();
+        let quic_settings = listener_settings.clone();
+        tokio::spawn(async move {
+            http3::serve(
+                https_addr,
+                &rustls_config,
+                quic_routes,
+                quic_settings,
+            )
+            .await;
+        });
+        http3::advertise(routes, args.https_port)
+    };
+
+    // let listener = tokio::net::TcpListener::bind(https_addr).await.unwrap();
+    // axum_server::Server::from_tcp(listener.into_std().unwrap())
+    //     .acceptor(axum_server::tls_openssl::OpenSSLAcceptor::new(tls_conf))
+    //     .http_builder()
+    //     .http1_only()
+    //     .serve_connection(TokioIo, routes.into_make_service());
+
+    listener::serve_https(https_addr, rustls_config, routes, listener_settings)
+        .await;
+}
+
+/// Load the certificates, start ACME if configured and build the TLS config
+/// of the HTTPS listener
+fn tls_config(
+    args: &Args,
+    config: &config::Config,
+    acme_tokens: &acme::Http01Tokens,
+) -> Arc<ServerConfig> {
+    let acme = acme::AcmeSettings::from_args(args);
+
+    if let Some(acme) = &acme {
+        if let Err(e) = acme::ensure_placeholder(acme) {
+            log::error!("Failed to set up ACME storage: \"{e}\"");
+            std::process::exit(1);
+        }
+    }
+
+    // the ACME certificate is the default one unless a certificate was given,
+    // in which case it is chosen by SNI like the configured ones
+    let mut by_name = config.tls.certificates.clone();
+    let default_cert = match (&args.cert_file, &args.key_file, &acme) {
+        (Some(cert), Some(key), acme) => {
+            by_name.extend(acme.as_ref().map(|a| a.cert_paths()));
+            config::CertPaths {
+                cert: cert.clone(),
+                key: key.clone(),
+                ocsp: args.ocsp_file.clone(),
+            }
+        }
+        (_, _, Some(acme)) => acme.cert_paths(),
+        _ => {
+            log::error!(
+                "Serving HTTPS needs --cert and --key, or --acme-domain. Use \
+                 --mode http to serve without TLS"
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let cert_resolver = match tls::CertResolver::load(default_cert, by_name) {
+        Ok(r) => Arc::new(r),
         Err(e) => {
             log::error!("Failed to open PEM files: \"{e}\"");
             std::process::exit(1);
         }
     };
 
-    rustls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
-    let tls_acceptor = TlsAcceptor::from(Arc::new(rustls_config));
+    tls::watch_certificates(cert_resolver.clone());
 
-    tokio::spawn(upgrade::redirect_http_to_https(args.clone()));
+    let alpn_challenge = acme
+        .as_ref()
+        .is_some_and(|a| a.challenge == AcmeChallenge::TlsAlpn01);
 
-    module_handler::module_listener(modules.clone(), &args).await;
+    if let Some(acme) = acme {
+        acme::spawn(acme, cert_resolver.clone(), acme_tokens.clone());
+    }
 
-    let default_redirect = args.default_redirect;
-    let routes = Router::new()
+    let mut rustls_config = match tls::server_config(&config.tls, cert_resolver)
+    {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Invalid TLS configuration: \"{e}\"");
+            std::process::exit(1);
+        }
+    };
+
+    rustls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    if alpn_challenge {
+        rustls_config
+            .alpn_protocols
+            .push(acme::ACME_TLS_ALPN.to_vec());
+    }
+
+    Arc::new(rustls_config)
+}
+
+/// The routes serving Slot's pages and forwarding to modules
+fn router(app_state: state::AppState, default_redirect: String) -> Router {
+    Router::new()
         .route(
             "/favicon.ico",
             get(async || -> Response {
@@ -77,156 +231,108 @@ async fn main() {
                 }
             }),
         )
-        .route("/{modname}/{*rest}", any(module_redirect))
+        .route("/{modname}", any(forward::module_root))
+        .route("/{modname}/", any(forward::module_index))
+        .route("/{modname}/{*rest}", any(forward::module_redirect))
         .route(
             "/",
             get(async move || Redirect::temporary(&default_redirect)),
         )
-        .with_state(modules);
-
-    let https_addr = SocketAddr::new(args.web_addr, args.https_port);
+        .fallback(forward::not_found)
+        // modules which claimed the requested host take priority over the
+        // path based routes
+        .layer(axum::middleware::from_fn_with_state(
+            app_state.clone(),
+            forward::route_by_host,
+        ))
+        .with_state(app_state)
+}
 
-    log::info!("Webserver listening on {https_addr}");
-    // let listener = tokio::net::TcpListener::bind(https_addr).await.unwrap();
-    // axum_server::Server::from_tcp(listener.into_std().unwrap())
-    //     .acceptor(axum_server::tls_openssl::OpenSSLAcceptor::new(tls_conf))
-    //     .http_builder()
-    //     .http1_only()
-    //     .serve_connection(TokioIo, routes.into_make_service());
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-    let tcp_listener = tokio::net::TcpListener::bind(https_addr).await.unwrap();
+    use axum::http::HeaderMap;
+    use slot_client::{forwarded, protocol::JoinOptions};
 
-    loop {
-        let tower_service = routes.clone();
-        let tls_acceptor = tls_acceptor.clone();
+    use super::*;
+    use crate::{config::Config, store::HttpAddr, test_util};
 
-        // Wait for new tcp connection
-        let (cnx, addr) = tcp_listener.accept().await.unwrap();
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
 
-        tokio::spawn(async move {
-            // Wait for tls handshake to happen
-            let Ok(stream) = tls_acceptor.accept(cnx).await else {
-                log::error!(
-                    "Error during TLS handshake connection from {addr}"
-                );
-                return;
-            };
-
-            // Hyper has its own `AsyncRead` and `AsyncWrite` traits and doesn't
-            // use tokio. `TokioIo` converts between them.
-            let stream = TokioIo::new(stream);
-
-            // Hyper also has its own `Service` trait and doesn't use tower. We
-            // can use `hyper::service::service_fn` to create a hyper `Service`
-            // that calls our app through `tower::Service::call`.
-            let hyper_service = hyper::service::service_fn(
-                move |request: Request<Incoming>| {
-                    // We have to clone `tower_service` because hyper's
-                    // `Service` uses `&self` whereas tower's `Service` requires
-                    // `&mut self`.
-                    // We don't need to call `poll_ready` since `Router` is
-                    // always ready.
-                    tower_service.clone().call(request)
-                },
-            );
+    /// Run Slot with `args` and a module "mod" which answers with the
+    /// X-Forwarded-Proto it received. Modules can't join over the network
+    async fn run_slot(args: &[&str]) {
+        let module = Router::new().fallback(async |headers: HeaderMap| {
+            headers[forwarded::FORWARDED_PROTO]
+                .to_str()
+                .unwrap()
+                .to_owned()
+        });
+        let upstream = test_util::serve(module).await;
+        let modules = store::ModuleStore::new();
+        let options = JoinOptions::default();
+        test_util::add_module(
+            &modules,
+            "mod",
+            HttpAddr::Tcp(upstream),
+            options,
+        )
+        .await;
 
-            let ret = hyper_util::server::conn::auto::Builder::new(
-                TokioExecutor::new(),
-            )
-            .http1_only()
-            .serve_connection_with_upgrades(stream, hyper_service)
-            .await;
+        let mut config = Config::default();
+        config.control.localhost = false;
 
-            if let Err(err) = ret {
-                log::warn!("Error serving connection from {addr}: {err}");
-            }
-        });
+        tokio::spawn(serve(test_util::args(args), config, modules));
     }
-}
 
-async fn module_redirect(
-    State(state): State<ModuleStore>,
-    Path((modname, modurl)): Path<(String, String)>,
-    req: Request,
-) -> Response {
-    // use the first segment of the URL endpoint to look up the module
-    let module_info = state.find_module_by_name(&modname).await;
-
-    if let Some(module_info) = module_info {
-        log::debug!("Redirecting request to module \"{}\"", module_info.name);
-
-        // set up reqwest client with request headers
-        let req_client = reqwest::Client::new();
-        // let req_client = reqwest::ClientBuilder::new().http2_prior_knowledge().build().unwrap();
-
-        // perform request forwarding to module
-        let url =
-            format!("http://{}/{modname}/{modurl}", module_info.http_addr);
-
-        // TODO: filter necessary headers (e.g., auth)
-
-        // - host: localhost:8001
-        // - user-agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:143.0) Gecko/20100101 Firefox/143.0
-        // - accept: audio/webm,audio/ogg,audio/wav,audio/*;q=0.9,application/ogg;q=0.7,video/*;q=0.6,*/*;q=0.5
-        // - accept-language: en-US,en;q=0.5
-        // - range: bytes=2654208-
-        // - sec-gpc: 1
-        // - connection: keep-alive
-        // - referer: https://localhost:8001/meta/index
-        // - sec-fetch-dest: audio
-        // - sec-fetch-mode: no-cors
-        // - sec-fetch-site: same-origin
-        // - accept-encoding: identity
-        // - priority: u=4
-        // - pragma: no-cache
-        // - cache-control: no-cache
-
-        let Ok(mod_resp) = req_client
-            .request(req.method().clone(), url)
-            // .headers(req.headers().clone())
-            // .version(req.version())
-            .send()
-            .await
-        else {
-            return Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(
-                    format!(
-                        "Module \"{modname}\" did not respond or unable to \
-                         make request"
-                    )
-                    .into(),
-                )
-                .unwrap();
-        };
-
-        // convert reqwest Response into axum Response
-        let mut resp = Response::builder();
-
-        // set headers in response
-        for (k, v) in mod_resp.headers().iter() {
-            if ["content-type", "cache-control"]
-                .contains(&k.as_str().to_lowercase().as_str())
-            {
-                resp = resp.header(k, v);
+    /// The body of a GET of `url`, retried until Slot is listening
+    async fn get_when_up(url: &str) -> String {
+        for _ in 0..100 {
+            if let Ok(resp) = reqwest::get(url).await {
+                assert_eq!(resp.status(), StatusCode::OK);
+                return resp.text().await.unwrap();
             }
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
+        panic!("nothing listening at {url}");
+    }
 
-        // // set extensions in response
-        // resp = resp.extension(mod_resp.extensions().clone());
+    #[tokio::test]
+    async fn http_mode_needs_no_certificate() {
+        let port = free_port().to_string();
+        run_slot(&["--mode", "http", "--http-bind", &port]).await;
 
-        // // set version
-        // resp = resp.version(mod_resp.version());
+        let url = format!("http://127.0.0.1:{port}/mod/");
+        assert_eq!(get_when_up(&url).await, "http");
+    }
 
-        resp.body(axum::body::Body::from(mod_resp.bytes().await.unwrap()))
-            .unwrap()
-    } else {
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(
-                format!("Module \"{modname}\" is offline or does not exist")
-                    .into(),
-            )
-            .unwrap()
+    #[tokio::test]
+    async fn both_mode_tells_modules_about_plain_http() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = test_util::self_signed(dir.path(), "slot", &["slot"]);
+        let http_port = free_port().to_string();
+        let https_port = free_port().to_string();
+
+        run_slot(&[
+            "--mode",
+            "both",
+            "--http-bind",
+            &http_port,
+            "--https-bind",
+            &https_port,
+            "--cert",
+            &cert.cert,
+            "--key",
+            &cert.key,
+        ])
+        .await;
+
+        let url = format!("http://127.0.0.1:{http_port}/mod/");
+        assert_eq!(get_when_up(&url).await, "http");
     }
 }
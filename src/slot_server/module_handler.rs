@@ -1,11 +1,29 @@
-use slot_client::protocol::{self, ValidName};
+use slot_client::{
+    client2::ServerConnection,
+    protocol::{self, JoinOptions, PathPolicy, ValidName},
+    protocol2::{self, ClientMsg, RejectReason, ServerMsg},
+};
 use std::{
+    ffi::OsString,
+    fs::{DirBuilder, Permissions},
+    io::ErrorKind,
     net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+    path::Path,
     time::{Duration, Instant},
 };
-use tokio::{net::UdpSocket, time::sleep};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{unix::UCred, TcpListener, UdpSocket, UnixListener},
+    time::{sleep, timeout},
+};
 
-use crate::{cli::Args, store::ModuleStore};
+use crate::{
+    auth::JoinAuthenticator,
+    cli::Args,
+    config::{Config, ControlConfig},
+    store::{ControlProtocol, HttpAddr, ModuleStore, SlotAddr},
+};
 
 // TODO: move these to protocol since they must be coordinated with the client
 const PING_DELAY_SEC: Duration = Duration::from_secs(5);
@@ -13,7 +31,37 @@ const SOCK_FAIL_BEFORE_RESTART: u8 = 5;
 const SPAM_DELAY: Duration = Duration::from_secs(1);
 const DEATH_TIMER: Duration = Duration::from_secs(10);
 
-pub async fn module_listener(module_store: ModuleStore, args: &Args) {
+pub async fn module_listener(
+    module_store: ModuleStore,
+    args: &Args,
+    config: &Config,
+) {
+    let control = &config.control;
+    let authenticator = JoinAuthenticator::new(config);
+
+    if let Some(path) = &control.socket {
+        let listener = match bind_unix(path, control.socket_mode) {
+            Ok(l) => l,
+            Err(e) => {
+                log::error!("Unable to listen on \"{path}\": \"{e}\"");
+                std::process::exit(1);
+            }
+        };
+        log::info!("Accepting modules on Unix socket \"{path}\"");
+
+        tokio::spawn(unix_listener(
+            module_store.clone(),
+            authenticator.clone(),
+            listener,
+            control.clone(),
+        ));
+    }
+
+    if !control.localhost {
+        log::info!("Not accepting modules on localhost");
+        return;
+    }
+
     let args = args.clone();
 
     tokio::spawn(async move {
@@ -22,6 +70,14 @@ pub async fn module_listener(module_store: ModuleStore, args: &Args) {
             args.slot_port,
         );
         log::info!("Starting Slot module thread. Listening on {slot_addr}");
+
+        // modules speaking version 2 connect over TCP on the same port
+        tokio::spawn(v2_listener(
+            module_store.clone(),
+            authenticator.clone(),
+            slot_addr,
+        ));
+
         let mut fail_count = 0u8;
         // Restart loop
         loop {
@@ -34,7 +90,7 @@ pub async fn module_listener(module_store: ModuleStore, args: &Args) {
                 }
             };
 
-            let mut buf = [0u8; protocol::PKT_LEN];
+            let mut buf = [0u8; protocol::MAX_PKT_LEN];
 
             // Listener loop
             loop {
@@ -45,15 +101,29 @@ pub async fn module_listener(module_store: ModuleStore, args: &Args) {
                 tokio::select! {
                     res = socket.recv_from(&mut buf) => {
                         match res {
-                            Ok((_, from_addr)) => {
+                            Ok((len, from_addr)) => {
                                 log::debug!("Slot listener received a packet");
-                                let msg = protocol::SlotMsg::from_bytes(buf);
+                                let msg = match protocol::SlotMsg::from_bytes(
+                                    &buf[..len]
+                                ) {
+                                    Ok(msg) => msg,
+                                    Err(e) => {
+                                        log::warn!(
+                                            "Dropping invalid packet from \
+                                             {from_addr}: {e}"
+                                        );
+                                        continue;
+                                    }
+                                };
 
                                 check_join_msg(
                                     &socket,
                                     &module_store,
+                                    &authenticator,
                                     &from_addr,
                                     &msg,
+                                    buf.get(protocol::PKT_LEN..len)
+                                        .unwrap_or_default(),
                                     &mut fail_count
                                 ).await;
 
@@ -62,6 +132,12 @@ pub async fn module_listener(module_store: ModuleStore, args: &Args) {
                                     &from_addr,
                                     &msg
                                 ).await;
+
+                                check_bye(
+                                    &module_store,
+                                    &from_addr,
+                                    &msg
+                                ).await;
                             },
                             Err(e) => {
                                 log::error!(
@@ -100,43 +176,65 @@ pub async fn module_listener(module_store: ModuleStore, args: &Args) {
 async fn check_join_msg(
     socket: &UdpSocket,
     module_store: &ModuleStore,
+    authenticator: &JoinAuthenticator,
     from_addr: &SocketAddr,
     pkt: &protocol::SlotMsg,
+    options: &[u8],
     fail_count: &mut u8,
 ) {
-    if pkt.cmd == protocol::MsgIds::Join as u8 {
-        let resp = protocol::SlotMsg {
-            cmd: protocol::MsgIds::RejectJoin as u8,
-            module_http_port: 0,
-            name_len: 0,
-            name: [0; _],
-        }
-        .as_bytes();
+    if pkt.cmd == protocol::MsgIds::Join {
+        let resp =
+            protocol::SlotMsg::new(protocol::MsgIds::RejectJoin).as_bytes();
 
-        let name = ValidName::new(pkt.name_len, pkt.name);
+        let name = &pkt.name;
 
-        if pkt.module_http_port == 0 {
-            socket.send_to(&resp, from_addr).await.ok();
-
-            log::warn!(
-                "Module \"{name}\" rejected because their HTTP port was invalid"
-            );
-            return;
-        }
+        // modules which don't send options get the defaults
+        let mut options = if options.is_empty() {
+            JoinOptions::default()
+        } else {
+            match JoinOptions::from_bytes(options) {
+                Ok(o) => o,
+                Err(e) => {
+                    socket.send_to(&resp, from_addr).await.ok();
 
-        let resp = protocol::SlotMsg {
-            cmd: protocol::MsgIds::ConfrimJoin as u8,
-            module_http_port: 0,
-            name_len: 0,
-            name: [0; _],
-        }
-        .as_bytes();
+                    log::warn!(
+                        "Module \"{name}\" rejected because their join \
+                         options were invalid: \"{e}\""
+                    );
+                    return;
+                }
+            }
+        };
 
-        // copying out since compiler complains due to packed field access
         let http_port = pkt.module_http_port;
 
-        let their_http_addr =
-            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), http_port);
+        let their_http_addr = match check_join(
+            module_store,
+            authenticator,
+            name,
+            http_port,
+            &mut options,
+            SlotAddr::Inet(*from_addr),
+            None,
+        )
+        .await
+        {
+            Ok(addr) => addr,
+            Err(e) => {
+                socket.send_to(&resp, from_addr).await.ok();
+
+                // the original protocol can't carry the reason, so this log is
+                // the only place it shows up
+                log::warn!(
+                    "Module \"{name}\" on {from_addr} rejected: {e}. It wasn't \
+                     told why, as it uses the original protocol"
+                );
+                return;
+            }
+        };
+
+        let resp =
+            protocol::SlotMsg::new(protocol::MsgIds::ConfrimJoin).as_bytes();
 
         if let Err(e) = socket.send_to(&resp, from_addr).await {
             log::error!(
@@ -147,20 +245,522 @@ async fn check_join_msg(
         }
 
         module_store
-            .store_module(&name, &their_http_addr, from_addr)
+            .store_module(
+                name,
+                their_http_addr,
+                SlotAddr::Inet(*from_addr),
+                ControlProtocol::Legacy,
+                options,
+            )
             .await;
 
         log::info!("Added module \"{name}\". HTTP port: {http_port}");
     }
 }
 
+/// Accept modules speaking version 2 of the protocol
+async fn v2_listener(
+    module_store: ModuleStore,
+    authenticator: JoinAuthenticator,
+    slot_addr: SocketAddr,
+) {
+    let listener = loop {
+        match TcpListener::bind(slot_addr).await {
+            Ok(l) => break l,
+            Err(e) => {
+                log::error!("Unable to bind to socket address: \"{e}\"");
+                sleep(SPAM_DELAY).await;
+            }
+        }
+    };
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(serve_v2_module(
+                    module_store.clone(),
+                    authenticator.clone(),
+                    stream,
+                    SlotAddr::Inet(addr),
+                    None,
+                ));
+            }
+            Err(e) => {
+                log::error!(
+                    "Error accepting connection from a module: \"{e}\""
+                );
+                sleep(SPAM_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Create the Unix socket modules register over, with permissions `mode`
+///
+/// The socket is made in a new directory only we can enter and moved into
+/// place once its permissions are set, so nobody can connect in between
+fn bind_unix(path: &str, mode: u32) -> std::io::Result<UnixListener> {
+    let path = Path::new(path);
+
+    // a socket left behind by an earlier run is replaced. anything else at the
+    // path, including a socket something still listens on, is left alone
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("\"{}\" exists and is not a socket", path.display()),
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("\"{}\" is in use by another server", path.display()),
+            ));
+        }
+    }
+
+    let Some(file_name) = path.file_name() else {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("\"{}\" is not a file path", path.display()),
+        ));
+    };
+    let mut staging_name = OsString::from(".");
+    staging_name.push(file_name);
+    staging_name.push(format!(".{}", std::process::id()));
+    let staging = path.with_file_name(staging_name);
+
+    DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+
+    // the socket is only left in the directory if something failed
+    std::fs::remove_file(&staged).ok();
+    std::fs::remove_dir(&staging).ok();
+    bound
+}
+
+/// Accept modules over the Unix socket, if their user or group is allowed by
+/// `control`
+async fn unix_listener(
+    module_store: ModuleStore,
+    authenticator: JoinAuthenticator,
+    listener: UnixListener,
+    control: ControlConfig,
+) {
+    let mut next_id = 0;
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::error!(
+                    "Error accepting connection from a module: \"{e}\""
+                );
+                sleep(SPAM_DELAY).await;
+                continue;
+            }
+        };
+
+        next_id += 1;
+        let addr = SlotAddr::Unix(next_id);
+
+        let cred = match stream.peer_cred() {
+            Ok(c) => c,
+            Err(e) => {
+                log::warn!("Unable to identify the module on {addr}: \"{e}\"");
+                continue;
+            }
+        };
+
+        if !peer_allowed(&control, &cred) {
+            log::warn!(
+                "Refused module connection from UID {} GID {} (PID {}): not \
+                 in control.allowed_uids or control.allowed_gids",
+                cred.uid(),
+                cred.gid(),
+                cred.pid().map_or("unknown".to_owned(), |p| p.to_string())
+            );
+            continue;
+        }
+
+        log::debug!(
+            "Module connected on {addr} as UID {} GID {}",
+            cred.uid(),
+            cred.gid()
+        );
+
+        tokio::spawn(serve_v2_module(
+            module_store.clone(),
+            authenticator.clone(),
+            stream,
+            addr,
+            Some(cred.uid()),
+        ));
+    }
+}
+
+/// Whether the process behind a Unix socket connection may register
+fn peer_allowed(control: &ControlConfig, cred: &UCred) -> bool {
+    let unrestricted =
+        control.allowed_uids.is_empty() && control.allowed_gids.is_empty();
+
+    unrestricted
+        || control.allowed_uids.contains(&cred.uid())
+        || control.allowed_gids.contains(&cred.gid())
+}
+
+/// Talk to the module connected from `addr` for as long as it stays connected.
+/// `peer_uid` is the module's user, when it connected over the Unix socket
+async fn serve_v2_module<Transport>(
+    module_store: ModuleStore,
+    authenticator: JoinAuthenticator,
+    stream: Transport,
+    addr: SlotAddr,
+    peer_uid: Option<u32>,
+) where
+    Transport: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = ServerConnection::new(stream);
+
+    let joined = join_v2_module(
+        &module_store,
+        &authenticator,
+        &mut conn,
+        addr,
+        peer_uid,
+    );
+
+    let name = match joined.await {
+        Ok(name) => name,
+        Err(e) => {
+            log::warn!("Module connection from {addr} failed: {e}");
+            return;
+        }
+    };
+
+    let mut heartbeat = tokio::time::interval(protocol2::HEARTBEAT_INTERVAL);
+    let mut last_heard = Instant::now();
+
+    let reason = loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > protocol2::HEARTBEAT_TIMEOUT {
+                    break "it has not responded for a while".to_owned();
+                }
+                if let Err(e) = conn.send_msg(ServerMsg::Heartbeat).await {
+                    break format!("sending a heartbeat failed: \"{e}\"");
+                }
+            }
+            msg = conn.recv_msg() => match msg {
+                Some(Ok(ClientMsg::ReplyHeartbeat)) => {
+                    last_heard = Instant::now();
+                    let heard = module_store
+                        .update_last_heard(&addr, ControlProtocol::V2)
+                        .await;
+                    if !heard {
+                        break "another module joined under its name"
+                            .to_owned();
+                    }
+                }
+                Some(Ok(msg)) => break format!("it sent {msg:?}"),
+                Some(Err(e)) => break format!("its connection failed: \"{e}\""),
+                None => break "it disconnected".to_owned(),
+            }
+        }
+    };
+
+    module_store.remove_module(&addr, ControlProtocol::V2).await;
+    log::warn!("Module \"{name}\" removed because {reason}");
+}
+
+/// Agree on the protocol version with the module at `addr` and add it to
+/// `module_store` if it can join
+async fn join_v2_module<Transport>(
+    module_store: &ModuleStore,
+    authenticator: &JoinAuthenticator,
+    conn: &mut ServerConnection<Transport>,
+    addr: SlotAddr,
+    peer_uid: Option<u32>,
+) -> Result<ValidName, String>
+where
+    Transport: AsyncRead + AsyncWrite + Unpin,
+{
+    let recv = async |conn: &mut ServerConnection<Transport>| match timeout(
+        protocol2::HANDSHAKE_TIMEOUT,
+        conn.recv_msg(),
+    )
+    .await
+    {
+        Ok(Some(Ok(msg))) => Ok(msg),
+        Ok(Some(Err(e))) => Err(format!("invalid message: \"{e}\"")),
+        Ok(None) => Err("disconnected during the handshake".to_owned()),
+        Err(_) => Err("handshake timed out".to_owned()),
+    };
+    let send = async |conn: &mut ServerConnection<Transport>, msg| {
+        conn.send_msg(msg)
+            .await
+            .map_err(|e| format!("unable to send to module: \"{e}\""))
+    };
+
+    let ClientMsg::Hello { versions } = recv(conn).await? else {
+        return Err("expected Hello".to_owned());
+    };
+
+    let Some(version) =
+        protocol2::negotiate_version(protocol2::SUPPORTED_VERSIONS, &versions)
+    else {
+        let supported = protocol2::SUPPORTED_VERSIONS.to_vec();
+        send(
+            conn,
+            ServerMsg::Unsupported {
+                versions: supported,
+            },
+        )
+        .await?;
+        return Err(format!("no supported version in {versions:?}"));
+    };
+
+    send(conn, ServerMsg::Welcome { version }).await?;
+
+    let ClientMsg::Join {
+        name,
+        http_port,
+        mut options,
+    } = recv(conn).await?
+    else {
+        return Err("expected Join".to_owned());
+    };
+
+    let their_http_addr = match check_join(
+        module_store,
+        authenticator,
+        &name,
+        http_port,
+        &mut options,
+        addr,
+        peer_uid,
+    )
+    .await
+    {
+        Ok(addr) => addr,
+        Err(reason) => {
+            send(
+                conn,
+                ServerMsg::RejectJoin {
+                    reason: reason.clone(),
+                },
+            )
+            .await?;
+            return Err(format!("module \"{name}\" rejected: {reason}"));
+        }
+    };
+
+    send(conn, ServerMsg::ConfirmJoin).await?;
+
+    log::info!(
+        "Added module \"{name}\" using protocol version {version}. HTTP \
+         address: {their_http_addr}"
+    );
+
+    module_store
+        .store_module(
+            &name,
+            their_http_addr,
+            addr,
+            ControlProtocol::V2,
+            options,
+        )
+        .await;
+
+    Ok(name)
+}
+
+/// Decide whether the module at `slot_addr` may join as `name`, serving HTTP
+/// on `http_port` or the socket in its options. `peer_uid` is the module's
+/// user, when it joined over the Unix socket. Its options are normalized on
+/// success, and the address its HTTP is served on returned
+async fn check_join(
+    module_store: &ModuleStore,
+    authenticator: &JoinAuthenticator,
+    name: &ValidName,
+    http_port: u16,
+    options: &mut JoinOptions,
+    slot_addr: SlotAddr,
+    peer_uid: Option<u32>,
+) -> Result<HttpAddr, RejectReason> {
+    let authenticated = authenticator.check(name, http_port, options).await?;
+
+    // a module registered under the name can only be replaced by the holder
+    // of the name's secret, or by itself joining again
+    if let Some(module_info) =
+        module_store.find_module_by_name(&name.to_string()).await
+    {
+        if !authenticated && module_info.slot_addr != slot_addr {
+            return Err(RejectReason::NameTaken);
+        }
+    }
+
+    let http_addr = match &options.http_socket {
+        Some(path) => check_http_socket(path, peer_uid)
+            .await
+            .map_err(RejectReason::Invalid)?,
+        None if http_port == 0 => {
+            return Err(RejectReason::Invalid(
+                "their HTTP port was invalid".to_owned(),
+            ));
+        }
+        None => HttpAddr::Tcp(SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            http_port,
+        )),
+    };
+
+    check_join_options(options).map_err(RejectReason::Invalid)?;
+
+    if let Some((host, owner)) =
+        module_store.find_host_conflict(name, &options.hosts).await
+    {
+        return Err(RejectReason::Invalid(format!(
+            "host \"{host}\" is already claimed by module \"{owner}\""
+        )));
+    }
+
+    Ok(http_addr)
+}
+
+/// Make sure a module may have its requests sent to the Unix socket at `path`.
+/// Only modules which joined over the Unix socket can use one, and it must be
+/// owned by their user, so a module can't have Slot forward requests to
+/// another user's socket. The address with the resolved path is returned
+async fn check_http_socket(
+    path: &str,
+    peer_uid: Option<u32>,
+) -> Result<HttpAddr, String> {
+    let Some(uid) = peer_uid else {
+        return Err(
+            "an HTTP socket can only be used by modules joining over the \
+             Unix socket"
+                .to_owned(),
+        );
+    };
+
+    if !path.starts_with('/') {
+        return Err(format!("HTTP socket \"{path}\" is not an absolute path"));
+    }
+
+    // symlinks are resolved now, so the checked socket is the one used
+    let resolved = tokio::fs::canonicalize(path)
+        .await
+        .map_err(|e| format!("HTTP socket \"{path}\" is unusable: \"{e}\""))?;
+    let metadata = tokio::fs::metadata(&resolved)
+        .await
+        .map_err(|e| format!("HTTP socket \"{path}\" is unusable: \"{e}\""))?;
+
+    if !metadata.file_type().is_socket() {
+        return Err(format!("HTTP socket \"{path}\" is not a socket"));
+    }
+
+    if metadata.uid() != uid {
+        return Err(format!(
+            "HTTP socket \"{path}\" is not owned by the module's user"
+        ));
+    }
+
+    Ok(HttpAddr::Unix {
+        path: resolved,
+        uid,
+    })
+}
+
+/// Make sure the options a module asked for can be honored. Host claims are
+/// normalized to lowercase
+fn check_join_options(options: &mut JoinOptions) -> Result<(), String> {
+    if let PathPolicy::Rewrite(base) = &options.path {
+        let valid = base.starts_with('/')
+            && base.parse::<axum::http::uri::PathAndQuery>().is_ok()
+            && !base.contains('?');
+
+        if !valid {
+            return Err(format!("invalid rewrite base path \"{base}\""));
+        }
+    }
+
+    for host in options.hosts.iter_mut() {
+        *host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        let name = host.strip_prefix("*.").unwrap_or(host);
+
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+
+        if !valid {
+            return Err(format!("invalid host claim \"{host}\""));
+        }
+    }
+
+    Ok(())
+}
+
 async fn check_ping_response(
     module_store: &ModuleStore,
     from_addr: &SocketAddr,
     pkt: &protocol::SlotMsg,
 ) {
-    if pkt.cmd == protocol::MsgIds::Heartbeat as u8 {
-        module_store.update_last_heard(from_addr).await;
+    if pkt.cmd == protocol::MsgIds::Heartbeat {
+        module_store
+            .update_last_heard(
+                &SlotAddr::Inet(*from_addr),
+                ControlProtocol::Legacy,
+            )
+            .await;
+    }
+}
+
+/// Remove the module which joined from `from_addr` if it says Bye. Clients
+/// name themselves in it, except older ones which leave the name empty
+async fn check_bye(
+    module_store: &ModuleStore,
+    from_addr: &SocketAddr,
+    pkt: &protocol::SlotMsg,
+) {
+    if pkt.cmd == protocol::MsgIds::Bye {
+        let slot_addr = SlotAddr::Inet(*from_addr);
+
+        if pkt.name != ValidName::default() {
+            let name = pkt.name.to_string();
+            let joined_from = module_store
+                .find_module_by_name(&name)
+                .await
+                .map(|module_info| module_info.slot_addr);
+
+            if joined_from != Some(slot_addr) {
+                log::warn!(
+                    "Ignoring Bye for module \"{name}\" from {from_addr}, \
+                     which it didn't join from"
+                );
+                return;
+            }
+        }
+
+        let removed = module_store
+            .remove_module(&slot_addr, ControlProtocol::Legacy)
+            .await;
+
+        if let Some(module_info) = removed {
+            log::info!("Module \"{}\" left", module_info.name);
+        }
     }
 }
 
@@ -168,7 +768,10 @@ async fn cleanup_dead(module_store: &ModuleStore) {
     // iterate over modules and check how long its been since we last heard them
     let mut dead = Vec::new();
     module_store.get_vec().await.retain(|module_info| {
-        if Instant::now() - module_info.time_last_heard > DEATH_TIMER {
+        // version 2 modules are removed by their connection's task
+        if module_info.protocol == ControlProtocol::Legacy
+            && Instant::now() - module_info.time_last_heard > DEATH_TIMER
+        {
             dead.push(module_info.clone());
 
             log::warn!(
@@ -182,6 +785,8 @@ async fn cleanup_dead(module_store: &ModuleStore) {
             true
         }
     });
+
+    module_store.mark_departed(dead).await;
 }
 
 async fn ping_all_modules(
@@ -190,22 +795,25 @@ async fn ping_all_modules(
 ) -> bool {
     let mut sock_fail = false;
 
-    let ping_msg = protocol::SlotMsg {
-        cmd: protocol::MsgIds::Heartbeat as u8,
-        module_http_port: 0,
-        name_len: 0,
-        name: [0; _],
-    }
-    .as_bytes();
+    let ping_msg =
+        protocol::SlotMsg::new(protocol::MsgIds::Heartbeat).as_bytes();
+
+    for module_info in module_store
+        .get_vec()
+        .await
+        .iter_mut()
+        .filter(|m| m.protocol == ControlProtocol::Legacy)
+    {
+        let SlotAddr::Inet(slot_addr) = module_info.slot_addr else {
+            continue;
+        };
 
-    for module_info in module_store.get_vec().await.iter_mut() {
-        match socket.send_to(&ping_msg, module_info.slot_addr).await {
+        match socket.send_to(&ping_msg, slot_addr).await {
             Ok(_) => {
                 // module_info.time_last_pinged = Instant::now();
                 log::debug!(
-                    "Pinged module: \"{}\" at {}",
-                    module_info.name,
-                    module_info.slot_addr
+                    "Pinged module: \"{}\" at {slot_addr}",
+                    module_info.name
                 )
             }
             Err(e) => {
@@ -222,3 +830,362 @@ async fn ping_all_modules(
 
     sock_fail
 }
+
+#[cfg(test)]
+mod tests {
+    use slot_client::auth::JoinSecret;
+
+    use super::*;
+    use crate::config::ModuleConfig;
+
+    /// Check a join as "blog" from `port` with `options`
+    async fn check_blog_join(
+        module_store: &ModuleStore,
+        authenticator: &JoinAuthenticator,
+        port: u16,
+        mut options: JoinOptions,
+    ) -> Result<HttpAddr, RejectReason> {
+        check_join(
+            module_store,
+            authenticator,
+            &"blog".parse().unwrap(),
+            8080,
+            &mut options,
+            SlotAddr::Inet(([127, 0, 0, 1], port).into()),
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn only_signed_joins_take_over_a_name() {
+        let mut config = Config::default();
+        let module = ModuleConfig {
+            secret: Some("hunter2".to_owned()),
+            ..Default::default()
+        };
+        config.modules.insert("blog".to_owned(), module);
+        let authenticator = JoinAuthenticator::new(&config);
+        let signed = || {
+            let options = JoinOptions {
+                secret: Some(JoinSecret::from("hunter2")),
+                ..Default::default()
+            };
+            options.signed(&"blog".parse().unwrap(), 8080)
+        };
+
+        let module_store = ModuleStore::new();
+        let joined =
+            check_blog_join(&module_store, &authenticator, 1, signed()).await;
+        module_store
+            .store_module(
+                &"blog".parse().unwrap(),
+                joined.unwrap(),
+                SlotAddr::Inet(([127, 0, 0, 1], 1).into()),
+                ControlProtocol::V2,
+                JoinOptions::default(),
+            )
+            .await;
+
+        let unsigned = JoinOptions::default();
+        assert_eq!(
+            check_blog_join(&module_store, &authenticator, 2, unsigned)
+                .await
+                .unwrap_err(),
+            RejectReason::AuthRequired
+        );
+        assert!(check_blog_join(&module_store, &authenticator, 2, signed())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn unsigned_joins_only_take_free_names() {
+        let authenticator = JoinAuthenticator::new(&Config::default());
+        let module_store = ModuleStore::new();
+        crate::test_util::add_module(
+            &module_store,
+            "blog",
+            HttpAddr::Tcp(([127, 0, 0, 1], 8080).into()),
+            JoinOptions::default(),
+        )
+        .await;
+
+        // add_module registers the module as joined from port 1
+        let rejoin = JoinOptions::default();
+        assert!(check_blog_join(&module_store, &authenticator, 1, rejoin)
+            .await
+            .is_ok());
+
+        let takeover = JoinOptions::default();
+        assert_eq!(
+            check_blog_join(&module_store, &authenticator, 2, takeover)
+                .await
+                .unwrap_err(),
+            RejectReason::NameTaken
+        );
+    }
+
+    fn bye(name: &str) -> protocol::SlotMsg {
+        protocol::SlotMsg {
+            name: name.parse().unwrap(),
+            ..protocol::SlotMsg::new(protocol::MsgIds::Bye)
+        }
+    }
+
+    /// Whether a module named "blog" is registered
+    async fn blog_joined(module_store: &ModuleStore) -> bool {
+        module_store.find_module_by_name("blog").await.is_some()
+    }
+
+    #[tokio::test]
+    async fn legacy_bye_only_removes_its_own_module() {
+        let module_store = ModuleStore::new();
+        let joined_from: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let join = async || {
+            module_store
+                .store_module(
+                    &"blog".parse().unwrap(),
+                    HttpAddr::Tcp(([127, 0, 0, 1], 8080).into()),
+                    SlotAddr::Inet(joined_from),
+                    ControlProtocol::Legacy,
+                    JoinOptions::default(),
+                )
+                .await;
+        };
+        join().await;
+
+        let elsewhere = ([127, 0, 0, 1], 2).into();
+        check_bye(&module_store, &elsewhere, &bye("blog")).await;
+        assert!(blog_joined(&module_store).await);
+
+        check_bye(&module_store, &joined_from, &bye("news")).await;
+        assert!(blog_joined(&module_store).await);
+
+        check_bye(&module_store, &joined_from, &bye("blog")).await;
+        assert!(!blog_joined(&module_store).await);
+
+        // older clients don't name themselves
+        join().await;
+        let unnamed = protocol::SlotMsg::new(protocol::MsgIds::Bye);
+        check_bye(&module_store, &elsewhere, &unnamed).await;
+        assert!(blog_joined(&module_store).await);
+        check_bye(&module_store, &joined_from, &unnamed).await;
+        assert!(!blog_joined(&module_store).await);
+    }
+
+    /// Wait until whether "blog" is registered is `joined`
+    async fn wait_for_blog(module_store: &ModuleStore, joined: bool) {
+        let waited = timeout(Duration::from_secs(10), async {
+            while blog_joined(module_store).await != joined {
+                sleep(Duration::from_millis(50)).await;
+            }
+        });
+        waited.await.expect("module didn't join or leave in time");
+    }
+
+    #[tokio::test]
+    async fn legacy_client_leaves_on_shutdown() {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let module_store = ModuleStore::new();
+        let args = crate::test_util::args(&["-s", &port.to_string()]);
+        module_listener(module_store.clone(), &args, &Config::default()).await;
+
+        // the UDP socket is bound before the TCP one, and a join sent before
+        // then would only be retried after a while
+        let server: SocketAddr = ([127, 0, 0, 1], port).into();
+        while tokio::net::TcpStream::connect(server).await.is_err() {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let client = slot_client::client_impl::run_client(
+            port,
+            "blog".parse().unwrap(),
+            8080,
+        );
+        wait_for_blog(&module_store, true).await;
+
+        // nobody else can make the module leave
+        let forger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        forger
+            .send_to(&bye("blog").as_bytes(), server)
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert!(blog_joined(&module_store).await);
+
+        tokio::task::spawn_blocking(|| client.shutdown())
+            .await
+            .unwrap();
+        wait_for_blog(&module_store, false).await;
+        assert!(module_store.has_departed("blog").await);
+    }
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn control_socket_is_created_with_its_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slot.sock");
+
+        let _listener = bind_unix(path.to_str().unwrap(), 0o600).unwrap();
+
+        assert_eq!(mode(&path), 0o600);
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["slot.sock"]);
+    }
+
+    #[tokio::test]
+    async fn stale_control_socket_is_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slot.sock");
+        drop(bind_unix(path.to_str().unwrap(), 0o600).unwrap());
+
+        let listener = bind_unix(path.to_str().unwrap(), 0o660).unwrap();
+
+        assert_eq!(mode(&path), 0o660);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_files_are_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slot.sock");
+        std::fs::write(&path, "not a socket").unwrap();
+
+        let err = bind_unix(path.to_str().unwrap(), 0o600).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+    }
+
+    #[tokio::test]
+    async fn live_control_socket_is_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slot.sock");
+        let listener = bind_unix(path.to_str().unwrap(), 0o600).unwrap();
+
+        let err = bind_unix(path.to_str().unwrap(), 0o600).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+
+    /// Our own credentials, as seen by the other end of a Unix socket
+    fn own_cred() -> UCred {
+        let (ours, _theirs) = tokio::net::UnixStream::pair().unwrap();
+        ours.peer_cred().unwrap()
+    }
+
+    fn control(allowed_uids: &[u32], allowed_gids: &[u32]) -> ControlConfig {
+        ControlConfig {
+            allowed_uids: allowed_uids.to_vec(),
+            allowed_gids: allowed_gids.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_are_allowed_by_uid_or_gid() {
+        let cred = own_cred();
+        let (uid, gid) = (cred.uid(), cred.gid());
+        let (other_uid, other_gid) = (uid.wrapping_add(1), gid.wrapping_add(1));
+
+        assert!(peer_allowed(&control(&[], &[]), &cred));
+        assert!(peer_allowed(&control(&[uid], &[]), &cred));
+        assert!(peer_allowed(&control(&[], &[gid]), &cred));
+        assert!(peer_allowed(&control(&[other_uid], &[gid]), &cred));
+        assert!(peer_allowed(&control(&[uid], &[other_gid]), &cred));
+
+        assert!(!peer_allowed(&control(&[other_uid], &[]), &cred));
+        assert!(!peer_allowed(&control(&[], &[other_gid]), &cred));
+        assert!(!peer_allowed(&control(&[other_uid], &[other_gid]), &cred));
+    }
+
+    #[tokio::test]
+    async fn own_http_socket_is_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("http.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+        let uid = std::fs::metadata(&path).unwrap().uid();
+
+        let addr = check_http_socket(path.to_str().unwrap(), Some(uid))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            addr,
+            HttpAddr::Unix {
+                path: path.canonicalize().unwrap(),
+                uid
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn unusable_http_sockets_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("http.sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        let uid = std::fs::metadata(&socket).unwrap().uid();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let err = check_http_socket("http.sock", Some(uid)).await.unwrap_err();
+        assert!(err.contains("is not an absolute path"), "{err}");
+
+        let err = check_http_socket(file.to_str().unwrap(), Some(uid))
+            .await
+            .unwrap_err();
+        assert!(err.contains("is not a socket"), "{err}");
+
+        let missing = dir.path().join("missing.sock");
+        let err = check_http_socket(missing.to_str().unwrap(), Some(uid))
+            .await
+            .unwrap_err();
+        assert!(err.contains("is unusable"), "{err}");
+
+        let other_uid = uid.wrapping_add(1);
+        let err = check_http_socket(socket.to_str().unwrap(), Some(other_uid))
+            .await
+            .unwrap_err();
+        assert!(err.contains("is not owned by the module's user"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn http_socket_needs_a_unix_socket_join() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("http.sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+
+        // joined over TCP, so the module's user is unknown
+        let options = JoinOptions {
+            http_socket: Some(socket.to_str().unwrap().to_owned()),
+            ..Default::default()
+        };
+        let err = check_blog_join(
+            &ModuleStore::new(),
+            &JoinAuthenticator::new(&Config::default()),
+            1,
+            options,
+        )
+        .await
+        .unwrap_err();
+
+        let RejectReason::Invalid(message) = err else {
+            panic!("rejected with {err:?}");
+        };
+        assert!(message.contains("modules joining over the Unix socket"));
+    }
+}
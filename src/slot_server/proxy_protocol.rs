@@ -0,0 +1,384 @@
+//! Reads the PROXY protocol header load balancers put in front of a forwarded
+//! TCP connection, telling who the client really is
+//!
+//! Both the text (v1) and binary (v2) formats are understood. See
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How a v2 header starts
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// How a v1 header starts
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// Longest v1 header, line ending included
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY protocol header from the start of `stream`, leaving the rest
+/// of the stream untouched. Returns the client address, or `None` if the
+/// balancer connected on its own behalf, e.g., for a health check
+pub async fn read_header<S>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, String>
+where
+    S: AsyncRead + Unpin,
+{
+    // both versions start with at least 8 bytes that tell them apart
+    let mut start = [0; 8];
+    read(stream, &mut start).await?;
+
+    if start[..] == V2_SIGNATURE[..8] {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, start).await
+    } else {
+        Err("no PROXY protocol header".to_owned())
+    }
+}
+
+async fn read_v1<S>(
+    stream: &mut S,
+    start: [u8; 8],
+) -> Result<Option<SocketAddr>, String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = start.to_vec();
+
+    // the header has no length field, so it is read up to the line end one
+    // byte at a time to not consume anything after it
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err("PROXY protocol v1 header is too long".to_owned());
+        }
+        let mut byte = [0];
+        read(stream, &mut byte).await?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| "PROXY protocol v1 header isn't text".to_owned())?;
+
+    let invalid = || format!("invalid PROXY protocol v1 header \"{line}\"");
+
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid())?;
+            let destination: IpAddr =
+                destination.parse().map_err(|_| invalid())?;
+            let port: u16 = port.parse().map_err(|_| invalid())?;
+
+            // both addresses must be of the family the header names
+            let ipv4 = family == "TCP4";
+            if ip.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+                return Err(invalid());
+            }
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, String>
+where
+    S: AsyncRead + Unpin,
+{
+    // the rest of the signature, the version and command, the address family
+    // and the length of what follows
+    let mut header = [0; 8];
+    read(stream, &mut header).await?;
+
+    if header[..4] != V2_SIGNATURE[8..] {
+        return Err("invalid PROXY protocol v2 signature".to_owned());
+    }
+
+    let version = header[4] >> 4;
+    let command = header[4] & 0x0F;
+    let family = header[5];
+    let len = u16::from_be_bytes([header[6], header[7]]);
+
+    if version != 2 {
+        return Err(format!("unsupported PROXY protocol version {version}"));
+    }
+
+    // the addresses, followed by optional TLVs which aren't used
+    let mut body = vec![0; usize::from(len)];
+    read(stream, &mut body).await?;
+
+    // the balancer speaking for itself
+    if command == 0x0 {
+        return Ok(None);
+    }
+    if command != 0x1 {
+        return Err(format!("unknown PROXY protocol v2 command {command}"));
+    }
+
+    let too_short =
+        || format!("PROXY protocol v2 addresses don't fit in {len} bytes");
+
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            let body: &[u8; 12] = body
+                .get(..12)
+                .and_then(|b| b.try_into().ok())
+                .ok_or_else(too_short)?;
+            let ip = Ipv4Addr::from([body[0], body[1], body[2], body[3]]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6
+        0x21 => {
+            let body: &[u8; 36] = body
+                .get(..36)
+                .and_then(|b| b.try_into().ok())
+                .ok_or_else(too_short)?;
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // unspecified, UDP or Unix sockets say nothing about a TCP client
+        _ => Ok(None),
+    }
+}
+
+async fn read<S>(stream: &mut S, buf: &mut [u8]) -> Result<(), String>
+where
+    S: AsyncRead + Unpin,
+{
+    stream
+        .read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("unable to read PROXY protocol header: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::ConnectInfo, Router};
+    use ipnet::IpNet;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{config::ProxyProtocolConfig, listener, test_util};
+
+    /// Read a header from `bytes`, returning it and the bytes left after it
+    async fn parse(
+        bytes: &[u8],
+    ) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+        let mut stream = bytes;
+        let header = read_header(&mut stream).await;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        (header, rest)
+    }
+
+    /// A v2 header with `command`, `family` and `body`, with `len` as the
+    /// length of the body
+    fn v2(command: u8, family: u8, len: u16, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend(len.to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    fn v2_ipv4(rest: &[u8]) -> Vec<u8> {
+        let body = [192, 0, 2, 1, 10, 0, 0, 1, 0x30, 0x39, 0x01, 0xBB];
+        let mut header = v2(0x1, 0x11, body.len() as u16, &body);
+        header.extend(rest);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (header, rest) =
+            parse(b"PROXY TCP4 192.0.2.1 10.0.0.1 12345 443\r\nGET").await;
+        assert_eq!(header, Ok(Some("192.0.2.1:12345".parse().unwrap())));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (header, rest) =
+            parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 12345 443\r\n").await;
+        assert_eq!(header, Ok(Some("[2001:db8::1]:12345".parse().unwrap())));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (header, rest) = parse(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(header, Ok(None));
+        assert_eq!(rest, b"GET");
+
+        let (header, _) = parse(
+            b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n",
+        )
+        .await;
+        assert_eq!(header, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn v1_family_must_match_the_addresses() {
+        let (header, _) =
+            parse(b"PROXY TCP4 2001:db8::1 2001:db8::2 12345 443\r\n").await;
+        assert!(header.is_err());
+
+        let (header, _) =
+            parse(b"PROXY TCP6 192.0.2.1 10.0.0.1 12345 443\r\n").await;
+        assert!(header.is_err());
+
+        let (header, _) =
+            parse(b"PROXY TCP4 192.0.2.1 2001:db8::2 12345 443\r\n").await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_garbage_is_refused() {
+        for line in [
+            &b"PROXY TCP4 192.0.2.1 10.0.0.1 12345\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 123456 443\r\n",
+            b"PROXY TCP4 192.0.2.x 10.0.0.1 12345 443\r\n",
+            b"PROXY UDP4 192.0.2.1 10.0.0.1 12345 443\r\n",
+            b"PROXY \xff\xfe\r\n",
+        ] {
+            assert!(parse(line).await.0.is_err(), "{line:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_line_is_limited() {
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.resize(V1_MAX_LEN - 2, b'x');
+        line.extend(b"\r\n");
+        assert_eq!(parse(&line).await.0, Ok(None));
+
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.resize(V1_MAX_LEN - 1, b'x');
+        line.extend(b"\r\n");
+        assert!(parse(&line).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_line_needs_its_line_ending() {
+        let (header, _) =
+            parse(b"PROXY TCP4 192.0.2.1 10.0.0.1 12345 443\n").await;
+        assert!(header.is_err());
+
+        let (header, _) =
+            parse(b"PROXY TCP4 192.0.2.1 10.0.0.1 12345 443").await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let (header, rest) = parse(&v2(0x0, 0x00, 3, b"tlvGET")).await;
+        assert_eq!(header, Ok(None));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv4() {
+        let (header, rest) = parse(&v2_ipv4(b"\x16\x03\x01")).await;
+        assert_eq!(header, Ok(Some("192.0.2.1:12345".parse().unwrap())));
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv6() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut body = source.octets().to_vec();
+        body.extend(destination.octets());
+        body.extend(12345u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        // a TLV, which is skipped
+        body.extend([0x04, 0x00, 0x01, 0xFF]);
+
+        let mut bytes = v2(0x1, 0x21, body.len() as u16, &body);
+        bytes.extend(b"GET");
+        let (header, rest) = parse(&bytes).await;
+        assert_eq!(header, Ok(Some("[2001:db8::1]:12345".parse().unwrap())));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_addresses_must_fit() {
+        let (header, _) = parse(&v2(0x1, 0x11, 4, &[192, 0, 2, 1])).await;
+        assert!(header.is_err());
+
+        let (header, _) = parse(&v2(0x1, 0x21, 12, &[0; 12])).await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_length_past_the_data() {
+        let body = [192, 0, 2, 1, 10, 0, 0, 1, 0x30, 0x39, 0x01, 0xBB];
+        let (header, _) = parse(&v2(0x1, 0x11, u16::MAX, &body)).await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_unknown_version_or_command() {
+        let mut bytes = v2_ipv4(b"");
+        bytes[12] = 0x11;
+        assert!(parse(&bytes).await.0.is_err());
+
+        let mut bytes = v2_ipv4(b"");
+        bytes[12] = 0x2F;
+        assert!(parse(&bytes).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_signature_is_refused() {
+        let mut bytes = v2_ipv4(b"");
+        bytes[9] = b'X';
+        assert!(parse(&bytes).await.0.is_err());
+
+        let (header, _) = parse(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(header.is_err());
+
+        let (header, _) = parse(b"PROX").await;
+        assert!(header.is_err());
+    }
+
+    #[tokio::test]
+    async fn request_after_header_reaches_http() {
+        let routes = Router::new().fallback(
+            async |ConnectInfo(addr): ConnectInfo<SocketAddr>| addr.to_string(),
+        );
+        let trusted = ProxyProtocolConfig {
+            trusted: vec!["127.0.0.0/8".parse::<IpNet>().unwrap()],
+        };
+        let settings = listener::Settings::new(&test_util::args(&[]), &trusted);
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .and_then(|l| l.local_addr())
+            .unwrap();
+        tokio::spawn(listener::serve_http(addr, routes, settings));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let mut cnx = TcpStream::connect(addr).await.unwrap();
+        cnx.write_all(&v2_ipv4(
+            b"GET / HTTP/1.1\r\nHost: slot\r\nConnection: close\r\n\r\n",
+        ))
+        .await
+        .unwrap();
+
+        let mut resp = String::new();
+        cnx.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        assert!(resp.ends_with("192.0.2.1:12345"), "{resp}");
+    }
+}
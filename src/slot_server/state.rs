@@ -0,0 +1,94 @@
+//! Shared state of the web router
+
+use std::{sync::Arc, time::Duration};
+
+use axum::extract::FromRef;
+
+use crate::{
+    cli::Args,
+    config::{Config, Timeouts},
+    error::ErrorPages,
+    store::ModuleStore,
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    pub modules: ModuleStore,
+
+    /// Long-lived client used for all requests forwarded to modules so that
+    /// connections to them are pooled and kept alive
+    pub upstream: reqwest::Client,
+
+    /// Time allowed for connecting to a module outside of `upstream`
+    pub connect_timeout: Duration,
+
+    /// Timeouts for modules which don't have their own in `config`
+    pub default_timeouts: Timeouts,
+
+    pub config: Arc<Config>,
+
+    pub error_pages: Arc<ErrorPages>,
+}
+
+impl FromRef<AppState> for ModuleStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.modules.clone()
+    }
+}
+
+impl AppState {
+    pub fn new(modules: ModuleStore, args: &Args, config: Config) -> Self {
+        Self {
+            modules,
+            upstream: build_upstream_client(args),
+            connect_timeout: Duration::from_secs(args.upstream_connect_timeout),
+            default_timeouts: Timeouts {
+                idle: Duration::from_secs(args.upstream_read_timeout),
+                total: args.upstream_total_timeout.map(Duration::from_secs),
+            },
+            config: Arc::new(config),
+            error_pages: Arc::new(ErrorPages::load(
+                args.error_pages_dir.as_deref(),
+            )),
+        }
+    }
+
+    /// The timeouts which apply to requests forwarded to `modname`
+    pub fn timeouts(&self, modname: &str) -> Timeouts {
+        let mut timeouts = self.default_timeouts;
+
+        if let Some(module) = self.config.module(modname) {
+            if let Some(idle) = module.idle_timeout {
+                timeouts.idle = Duration::from_secs(idle);
+            }
+            if let Some(total) = module.total_timeout {
+                timeouts.total = Some(Duration::from_secs(total));
+            }
+        }
+
+        timeouts
+    }
+}
+
+/// Build the HTTP client used to talk to modules
+fn build_upstream_client(args: &Args) -> reqwest::Client {
+    // every module has its own address, so the per-host limit is a per-module
+    // limit. modules are always local so system proxies must not apply. read
+    // timeouts are per module and enforced while forwarding instead
+    let mut builder = reqwest::Client::builder()
+        .no_proxy()
+        .pool_max_idle_per_host(args.upstream_max_idle)
+        .connect_timeout(Duration::from_secs(args.upstream_connect_timeout));
+
+    if args.upstream_http2 {
+        builder = builder.http2_prior_knowledge();
+    }
+
+    match builder.build() {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("Failed to build HTTP client for modules: \"{e}\"");
+            std::process::exit(1);
+        }
+    }
+}
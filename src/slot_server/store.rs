@@ -1,24 +1,105 @@
-use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use slot_client::protocol::ValidName;
+use slot_client::protocol::{JoinOptions, ValidName};
 use tokio::sync::RwLock;
 
+/// How long a departed module's name is remembered
+const DEPARTED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many departed module names are remembered at most
+const MAX_DEPARTED: usize = 1024;
+
 #[derive(Debug, Clone)]
 pub struct ModuleInfo {
     pub name: ValidName,
-    pub http_addr: SocketAddr,
-    pub slot_addr: SocketAddr,
+    pub http_addr: HttpAddr,
+    pub slot_addr: SlotAddr,
+    pub protocol: ControlProtocol,
     pub time_last_heard: Instant,
+    pub options: JoinOptions,
+}
+
+/// Where a module serves HTTP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpAddr {
+    Tcp(SocketAddr),
+
+    /// A Unix socket, which must be served by the module's user `uid`
+    Unix {
+        path: PathBuf,
+        uid: u32,
+    },
+}
+
+impl HttpAddr {
+    /// The `Host` requests to the module are sent with
+    pub fn authority(&self) -> String {
+        match self {
+            Self::Tcp(addr) => addr.to_string(),
+            Self::Unix { .. } => "localhost".to_owned(),
+        }
+    }
+}
+
+impl Display for HttpAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The module's end of its control channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotAddr {
+    Inet(SocketAddr),
+
+    /// Unix socket peers have no address, so their connections are numbered
+    Unix(u64),
+}
+
+impl Display for SlotAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inet(addr) => write!(f, "{addr}"),
+            Self::Unix(id) => write!(f, "Unix socket connection {id}"),
+        }
+    }
+}
+
+/// How the Slot server and a module talk to each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlProtocol {
+    /// `SlotMsg` packets over UDP. The listener pings the module and removes
+    /// it when it stops answering
+    Legacy,
+
+    /// Version 2 over a TCP or Unix socket connection, whose task keeps the
+    /// module alive and removes it when the connection ends
+    V2,
 }
 
 pub struct ModuleStore {
     modules: Arc<RwLock<Vec<ModuleInfo>>>,
+    /// Names of modules which were registered but have since been removed,
+    /// with when they were removed
+    departed: Arc<RwLock<HashMap<ValidName, Instant>>>,
 }
 
 impl Clone for ModuleStore {
     fn clone(&self) -> Self {
         Self {
             modules: self.modules.clone(),
+            departed: self.departed.clone(),
         }
     }
 }
@@ -27,21 +108,41 @@ impl ModuleStore {
     pub fn new() -> Self {
         Self {
             modules: Arc::new(RwLock::new(Vec::with_capacity(8))),
+            departed: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// Add a module, replacing any module registered under the same name
     pub async fn store_module(
         &self,
         name: &ValidName,
-        http_addr: &SocketAddr,
-        slot_addr: &SocketAddr,
+        http_addr: HttpAddr,
+        slot_addr: SlotAddr,
+        protocol: ControlProtocol,
+        options: JoinOptions,
     ) {
-        self.modules.write().await.push(ModuleInfo {
+        self.departed.write().await.remove(name);
+
+        let mut modules = self.modules.write().await;
+
+        if let Some(index) = modules.iter().position(|e| &e.name == name) {
+            let replaced = modules.remove(index);
+            if replaced.slot_addr != slot_addr {
+                log::warn!(
+                    "Module \"{name}\" at {} was replaced by a new join",
+                    replaced.slot_addr
+                );
+            }
+        }
+
+        modules.push(ModuleInfo {
             name: name.clone(),
-            http_addr: *http_addr,
-            slot_addr: *slot_addr,
+            http_addr,
+            slot_addr,
+            protocol,
             time_last_heard: Instant::now(),
             // time_last_pinged: Instant::now(),
+            options,
         });
     }
 
@@ -55,20 +156,126 @@ impl ModuleStore {
             .cloned()
     }
 
-    pub async fn update_last_heard(&self, addr: &SocketAddr) {
-        if let Some(module_info) = self
-            .modules
-            .write()
+    /// Remove the module reached over `protocol` at `slot_addr`, remembering
+    /// that it departed
+    pub async fn remove_module(
+        &self,
+        slot_addr: &SlotAddr,
+        protocol: ControlProtocol,
+    ) -> Option<ModuleInfo> {
+        let mut modules = self.modules.write().await;
+        let index = modules.iter().position(|e| {
+            &e.slot_addr == slot_addr && e.protocol == protocol
+        })?;
+        let removed = modules.remove(index);
+        drop(modules);
+
+        self.mark_departed(vec![removed.clone()]).await;
+        Some(removed)
+    }
+
+    /// Remember that these modules were removed, so requests for them can be
+    /// told to come back later rather than that they don't exist. Names are
+    /// forgotten after `DEPARTED_TTL`, and the oldest first once there are
+    /// more than `MAX_DEPARTED`
+    pub async fn mark_departed(&self, removed: Vec<ModuleInfo>) {
+        let mut departed = self.departed.write().await;
+        let now = Instant::now();
+
+        for module_info in removed {
+            departed.insert(module_info.name, now);
+        }
+
+        departed.retain(|_, at| now.duration_since(*at) < DEPARTED_TTL);
+
+        if departed.len() > MAX_DEPARTED {
+            let mut by_age: Vec<_> =
+                departed.iter().map(|(n, at)| (*at, n.clone())).collect();
+            by_age.sort_unstable_by_key(|(at, _)| *at);
+
+            let excess = departed.len() - MAX_DEPARTED;
+            for (_, name) in by_age.into_iter().take(excess) {
+                departed.remove(&name);
+            }
+        }
+    }
+
+    pub async fn has_departed(&self, name: &str) -> bool {
+        let Ok(validated) = ValidName::from_str(name) else {
+            return false;
+        };
+        self.departed
+            .read()
+            .await
+            .get(&validated)
+            .is_some_and(|at| at.elapsed() < DEPARTED_TTL)
+    }
+
+    /// Find the module which claimed `host`, preferring exact claims over
+    /// wildcards and longer wildcards over shorter ones
+    pub async fn find_module_by_host(&self, host: &str) -> Option<ModuleInfo> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        self.modules
+            .read()
             .await
+            .iter()
+            .filter_map(|module_info| {
+                module_info
+                    .options
+                    .hosts
+                    .iter()
+                    .filter_map(|claim| claim_specificity(claim, &host))
+                    .max()
+                    .map(|specificity| (specificity, module_info))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, module_info)| module_info.clone())
+    }
+
+    /// Find a module other than `name` which already claimed one of `hosts`
+    pub async fn find_host_conflict(
+        &self,
+        name: &ValidName,
+        hosts: &[String],
+    ) -> Option<(String, ValidName)> {
+        self.modules
+            .read()
+            .await
+            .iter()
+            .filter(|module_info| &module_info.name != name)
+            .find_map(|module_info| {
+                module_info
+                    .options
+                    .hosts
+                    .iter()
+                    .find(|claim| hosts.contains(claim))
+                    .map(|claim| (claim.clone(), module_info.name.clone()))
+            })
+    }
+
+    /// Note that the module at `addr` is alive. Returns whether one is
+    /// registered there
+    pub async fn update_last_heard(
+        &self,
+        addr: &SlotAddr,
+        protocol: ControlProtocol,
+    ) -> bool {
+        let mut modules = self.modules.write().await;
+
+        let Some(module_info) = modules
             .iter_mut()
-            .find(|e| &e.slot_addr == addr)
-        {
-            log::debug!(
-                "Received heartbeat response from module \"{}\"",
-                module_info.name
-            );
-            module_info.time_last_heard = Instant::now();
-        }
+            .find(|e| &e.slot_addr == addr && e.protocol == protocol)
+        else {
+            return false;
+        };
+
+        log::debug!(
+            "Received heartbeat response from module \"{}\"",
+            module_info.name
+        );
+        module_info.time_last_heard = Instant::now();
+        true
     }
 
     pub async fn get_vec(
@@ -77,3 +284,177 @@ impl ModuleStore {
         self.modules.write().await
     }
 }
+
+/// How closely a host claim matches `host`, or `None` if it doesn't. Both are
+/// expected to be lowercase
+fn claim_specificity(claim: &str, host: &str) -> Option<usize> {
+    match claim.strip_prefix('*') {
+        // "*.example.com" matches any subdomain, but not "example.com"
+        Some(suffix) => (host.len() > suffix.len() && host.ends_with(suffix))
+            .then_some(suffix.len()),
+        None => (claim == host).then_some(usize::MAX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_info(name: &str) -> ModuleInfo {
+        ModuleInfo {
+            name: name.parse().unwrap(),
+            http_addr: HttpAddr::Tcp(([127, 0, 0, 1], 8000).into()),
+            slot_addr: SlotAddr::Inet(([127, 0, 0, 1], 1).into()),
+            protocol: ControlProtocol::V2,
+            time_last_heard: Instant::now(),
+            options: JoinOptions::default(),
+        }
+    }
+
+    /// Register `name` as joined from `port`, claiming `hosts`
+    async fn join(store: &ModuleStore, name: &str, port: u16, hosts: &[&str]) {
+        let options = JoinOptions {
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        };
+        store
+            .store_module(
+                &name.parse().unwrap(),
+                HttpAddr::Tcp(([127, 0, 0, 1], port).into()),
+                SlotAddr::Inet(([127, 0, 0, 1], port).into()),
+                ControlProtocol::V2,
+                options,
+            )
+            .await;
+    }
+
+    async fn host_owner(store: &ModuleStore, host: &str) -> Option<String> {
+        let module_info = store.find_module_by_host(host).await?;
+        Some(module_info.name.to_string())
+    }
+
+    #[tokio::test]
+    async fn claimed_host_conflicts_with_other_modules() {
+        let store = ModuleStore::new();
+        join(&store, "blog", 8001, &["blog.example.com", "*.example.org"])
+            .await;
+
+        let other: ValidName = "api".parse().unwrap();
+        let hosts =
+            ["api.example.com".to_owned(), "blog.example.com".to_owned()];
+        let conflict = store.find_host_conflict(&other, &hosts).await;
+        assert_eq!(
+            conflict,
+            Some(("blog.example.com".to_owned(), "blog".parse().unwrap()))
+        );
+
+        let hosts = ["*.example.org".to_owned()];
+        assert!(store.find_host_conflict(&other, &hosts).await.is_some());
+
+        let hosts = ["api.example.com".to_owned()];
+        assert!(store.find_host_conflict(&other, &hosts).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejoining_replaces_own_claims() {
+        let store = ModuleStore::new();
+        join(&store, "blog", 8001, &["blog.example.com"]).await;
+
+        // a module doesn't conflict with its own earlier claims
+        let name: ValidName = "blog".parse().unwrap();
+        let hosts = ["blog.example.com".to_owned()];
+        assert!(store.find_host_conflict(&name, &hosts).await.is_none());
+
+        join(&store, "blog", 8002, &["www.example.com"]).await;
+
+        assert_eq!(store.get_vec().await.len(), 1);
+        assert_eq!(host_owner(&store, "blog.example.com").await, None);
+        assert_eq!(
+            host_owner(&store, "www.example.com").await.as_deref(),
+            Some("blog")
+        );
+    }
+
+    #[tokio::test]
+    async fn heartbeats_only_count_for_their_protocol() {
+        let store = ModuleStore::new();
+        join(&store, "blog", 8001, &[]).await;
+
+        // a legacy module heartbeating from the same address as a v2 one
+        let slot_addr = SlotAddr::Inet(([127, 0, 0, 1], 8001).into());
+        assert!(
+            !store
+                .update_last_heard(&slot_addr, ControlProtocol::Legacy)
+                .await
+        );
+        assert!(
+            store
+                .update_last_heard(&slot_addr, ControlProtocol::V2)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn departure_releases_claims() {
+        let store = ModuleStore::new();
+        join(&store, "blog", 8001, &["blog.example.com"]).await;
+
+        let slot_addr = SlotAddr::Inet(([127, 0, 0, 1], 8001).into());
+        store.remove_module(&slot_addr, ControlProtocol::V2).await;
+
+        assert_eq!(host_owner(&store, "blog.example.com").await, None);
+
+        let other: ValidName = "api".parse().unwrap();
+        let hosts = ["blog.example.com".to_owned()];
+        assert!(store.find_host_conflict(&other, &hosts).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn most_specific_claim_wins() {
+        let store = ModuleStore::new();
+        join(&store, "any", 8001, &["*.example.com"]).await;
+        join(&store, "shop", 8002, &["*.shop.example.com"]).await;
+        join(&store, "www", 8003, &["www.shop.example.com"]).await;
+
+        let owner = |host| host_owner(&store, host);
+        assert_eq!(owner("blog.example.com").await.as_deref(), Some("any"));
+        assert_eq!(owner("a.shop.example.com").await.as_deref(), Some("shop"));
+        assert_eq!(
+            owner("WWW.shop.example.com.").await.as_deref(),
+            Some("www")
+        );
+        assert_eq!(owner("example.com").await, None);
+    }
+
+    #[tokio::test]
+    async fn departed_names_are_bounded() {
+        let store = ModuleStore::new();
+
+        let removed = (0..MAX_DEPARTED + 10)
+            .map(|i| module_info(&format!("mod{i}")))
+            .collect();
+        store.mark_departed(removed).await;
+
+        assert_eq!(store.departed.read().await.len(), MAX_DEPARTED);
+    }
+
+    #[tokio::test]
+    async fn rejoining_forgets_departure() {
+        let store = ModuleStore::new();
+        let info = module_info("mod");
+
+        store.mark_departed(vec![info.clone()]).await;
+        assert!(store.has_departed("mod").await);
+
+        store
+            .store_module(
+                &info.name,
+                info.http_addr,
+                info.slot_addr,
+                info.protocol,
+                info.options,
+            )
+            .await;
+        assert!(!store.has_departed("mod").await);
+    }
+}
@@ -0,0 +1,169 @@
+//! Helpers shared by the server's tests
+
+use std::{net::SocketAddr, path::Path};
+
+use axum::{
+    body::Body, extract::Request, http::StatusCode, response::Response, Router,
+};
+use clap::Parser;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use slot_client::protocol::{JoinOptions, ValidName};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+};
+use tower::ServiceExt;
+
+use crate::{
+    cli::Args,
+    config::{CertPaths, Config},
+    state::AppState,
+    store::{ControlProtocol, HttpAddr, ModuleStore, SlotAddr},
+};
+
+/// Command line arguments with the defaults, plus `extra`
+pub fn args(extra: &[&str]) -> Args {
+    let required = ["slot_server", "-r", "/"];
+    Args::parse_from(required.iter().chain(extra))
+}
+
+/// Serve `routes` on a free port on localhost
+pub async fn serve(routes: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, routes).await });
+    addr
+}
+
+/// Serve `routes` on a Unix socket at `path`
+pub fn serve_unix(routes: Router, path: &Path) {
+    let listener = UnixListener::bind(path).unwrap();
+    tokio::spawn(async move { axum::serve(listener, routes).await });
+}
+
+/// A module which answers every request with the request target it received
+pub fn echo_target() -> Router {
+    Router::new().fallback(async |req: Request| req.uri().to_string())
+}
+
+/// Slot's router with `modules` registered
+pub fn slot(modules: ModuleStore, config: Config) -> Router {
+    let state = AppState::new(modules, &args(&[]), config);
+    crate::router(state, "/".to_owned())
+}
+
+/// Register a module named `name` serving HTTP at `http_addr`
+pub async fn add_module(
+    modules: &ModuleStore,
+    name: &str,
+    http_addr: HttpAddr,
+    options: JoinOptions,
+) {
+    let name: ValidName = name.parse().unwrap();
+    let slot_addr = SlotAddr::Inet(([127, 0, 0, 1], 1).into());
+    modules
+        .store_module(&name, http_addr, slot_addr, ControlProtocol::V2, options)
+        .await;
+}
+
+/// Slot with `module` served on a free port and registered as "mod"
+pub async fn slot_with_module(
+    module: Router,
+    options: JoinOptions,
+    config: Config,
+) -> Router {
+    let upstream = serve(module).await;
+    let modules = ModuleStore::new();
+    add_module(&modules, "mod", HttpAddr::Tcp(upstream), options).await;
+    slot(modules, config)
+}
+
+/// Send `req` through `router`
+pub async fn send(router: &Router, req: Request) -> Response {
+    router.clone().oneshot(req).await.unwrap()
+}
+
+/// GET `uri` from `router`, returning the status and the body
+pub async fn get(router: &Router, uri: &str) -> (StatusCode, String) {
+    let req = Request::get(uri).body(Body::empty()).unwrap();
+    let resp = send(router, req).await;
+    let status = resp.status();
+    (status, body_text(resp).await)
+}
+
+pub async fn body_text(resp: Response) -> String {
+    let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Use ring for TLS, as the server does
+pub fn install_crypto_provider() {
+    // another test may have installed it already
+    let _ = CryptoProvider::install_default(ring::default_provider());
+}
+
+/// Write a new self-signed certificate for `sans`, and its key, to `dir` as
+/// "{name}.pem" and "{name}.key"
+pub fn self_signed(
+    dir: &Path,
+    name: &str,
+    sans: &[&str],
+) -> (CertPaths, CertificateDer<'static>) {
+    let key = KeyPair::generate().unwrap();
+    let sans: Vec<_> = sans.iter().map(|s| s.to_string()).collect();
+    let cert = CertificateParams::new(sans)
+        .unwrap()
+        .self_signed(&key)
+        .unwrap();
+
+    let paths = CertPaths {
+        cert: dir.join(format!("{name}.pem")).display().to_string(),
+        key: dir.join(format!("{name}.key")).display().to_string(),
+        ocsp: None,
+    };
+    std::fs::write(&paths.cert, cert.pem()).unwrap();
+    std::fs::write(&paths.key, key.serialize_pem()).unwrap();
+
+    (paths, cert.der().clone())
+}
+
+/// Write a new CA certificate to `dir` as "{name}.pem", returning the CA and
+/// the path
+pub fn ca(
+    dir: &Path,
+    name: &str,
+) -> (CertifiedIssuer<'static, KeyPair>, String) {
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+
+    let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap())
+        .unwrap();
+
+    let path = dir.join(format!("{name}.pem")).display().to_string();
+    std::fs::write(&path, ca.pem()).unwrap();
+    (ca, path)
+}
+
+/// A new client certificate for `common_name` issued by `ca`, and its key
+pub fn client_cert(
+    ca: &CertifiedIssuer<'static, KeyPair>,
+    common_name: &str,
+) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let cert = params.signed_by(&key, ca).unwrap();
+
+    let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+    (vec![cert.der().clone()], key)
+}
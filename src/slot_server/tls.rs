@@ -0,0 +1,880 @@
+//! TLS certificate management
+//!
+//! Certificates are served through a resolver whose contents can be swapped
+//! while the server runs. The PEM files are reloaded when they change on disk
+//! or when the process receives SIGHUP. A new certificate that fails to load
+//! is logged and the previous ones stay in use.
+//!
+//! Besides the default certificate, any number of additional certificates can
+//! be configured. Each handshake gets the first of them whose SANs name the
+//! server name (SNI) the client sent, or the default one if none do. Names are
+//! read from the certificates when they are loaded, and an exact name is
+//! preferred over a wildcard.
+//!
+//! When a client CA bundle is configured, clients are asked for a certificate
+//! but may connect without one. Whether a certificate is required is decided
+//! per module when the request is forwarded.
+//!
+//! The protocol versions, cipher suites and session resumption come from the
+//! `[tls]` section of the config file. An OCSP response can be stapled to each
+//! certificate. It is read from a file next to the certificate and reloaded
+//! along with it, so an external job can keep it fresh.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use ::ring::{
+    aead,
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider, GetRandomFailed},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{
+        danger::ClientCertVerifier, ClientHello, NoServerSessionStorage,
+        ProducesTickets, ResolvesServerCert, ServerSessionMemoryCache,
+        WebPkiClientVerifier,
+    },
+    sign::CertifiedKey,
+    ticketer::TicketRotator,
+    version, RootCertStore, ServerConfig, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+
+use x509_parser::extensions::GeneralName;
+
+use crate::{
+    acme,
+    config::{CertPaths, TlsConfig, TlsVersion},
+    forward::ClientCert,
+};
+
+/// How often the PEM files are checked for changes
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// All certificates the server can present
+#[derive(Debug)]
+struct CertSet {
+    default: Arc<CertifiedKey>,
+
+    /// Certificates by the DNS names in their SANs, lowercase. Wildcard names
+    /// are kept with the "*." in front
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertSet {
+    /// Load the default certificate and the ones chosen by SNI. Fails if any
+    /// of them can't be loaded
+    fn load(
+        default: &CertPaths,
+        by_name: &[CertPaths],
+    ) -> Result<Self, String> {
+        let mut names = HashMap::new();
+
+        for paths in by_name {
+            let key = Arc::new(load_certified_key(paths)?);
+
+            // the first certificate listed for a name wins
+            for name in dns_names(&key, &paths.cert)? {
+                names.entry(name).or_insert_with(|| key.clone());
+            }
+        }
+
+        Ok(Self {
+            default: Arc::new(load_certified_key(default)?),
+            by_name: names,
+        })
+    }
+
+    /// The certificate to present to a client which asked for `server_name`.
+    /// A certificate for the exact name is preferred over a wildcard one
+    fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(server_name) = server_name else {
+            return self.default.clone();
+        };
+        let server_name = server_name.to_ascii_lowercase();
+
+        // a wildcard only stands for the leftmost label
+        let wildcard = server_name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{parent}"));
+
+        self.by_name
+            .get(&server_name)
+            .or_else(|| self.by_name.get(&wildcard?))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// The DNS names `key`'s certificate, read from `path`, is valid for,
+/// lowercase
+fn dns_names(key: &CertifiedKey, path: &str) -> Result<Vec<String>, String> {
+    let end_entity = key
+        .end_entity_cert()
+        .map_err(|e| format!("no certificate in \"{path}\": {e}"))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(end_entity)
+        .map_err(|e| format!("unable to parse \"{path}\": {e}"))?;
+    let san = cert
+        .subject_alternative_name()
+        .map_err(|e| format!("invalid SANs in \"{path}\": {e}"))?;
+
+    let names: Vec<String> = san
+        .iter()
+        .flat_map(|san| &san.value.general_names)
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect();
+
+    if names.is_empty() {
+        log::warn!(
+            "Certificate \"{path}\" has no DNS names in its SANs, so it is \
+             never chosen by SNI"
+        );
+    }
+
+    Ok(names)
+}
+
+/// Serves from whichever set of certificates was loaded most recently
+#[derive(Debug)]
+pub struct CertResolver {
+    default: CertPaths,
+    by_name: Vec<CertPaths>,
+    current: RwLock<Arc<CertSet>>,
+
+    /// Certificates answering ACME TLS-ALPN-01 challenges, keyed by domain
+    alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        client_hello: ClientHello<'_>,
+    ) -> Option<Arc<CertifiedKey>> {
+        let is_acme_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protos| protos.any(|p| p == acme::ACME_TLS_ALPN));
+
+        // challenge handshakes only ever get the challenge certificate
+        if is_acme_challenge {
+            let domain = client_hello.server_name()?;
+            return self.alpn_challenges.read().unwrap().get(domain).cloned();
+        }
+
+        let certs = self.current.read().unwrap().clone();
+        Some(certs.select(client_hello.server_name()))
+    }
+}
+
+impl CertResolver {
+    pub fn load(
+        default: CertPaths,
+        by_name: Vec<CertPaths>,
+    ) -> Result<Self, String> {
+        let certs = CertSet::load(&default, &by_name)?;
+
+        Ok(Self {
+            default,
+            by_name,
+            current: RwLock::new(Arc::new(certs)),
+            alpn_challenges: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Load all certificates from their files again. Handshakes already in
+    /// progress keep the one they started with. If any certificate fails to
+    /// load, the current ones stay in use
+    pub fn reload(&self) -> Result<(), String> {
+        let certs = CertSet::load(&self.default, &self.by_name)?;
+        *self.current.write().unwrap() = Arc::new(certs);
+        Ok(())
+    }
+
+    /// The files of every certificate served
+    pub fn paths(&self) -> impl Iterator<Item = &CertPaths> {
+        std::iter::once(&self.default).chain(&self.by_name)
+    }
+
+    /// Present `key` to ACME servers validating `domain` with TLS-ALPN-01, or
+    /// stop doing so if `key` is `None`
+    pub fn set_alpn_challenge(&self, domain: &str, key: Option<CertifiedKey>) {
+        let mut challenges = self.alpn_challenges.write().unwrap();
+        match key {
+            Some(key) => challenges.insert(domain.to_owned(), Arc::new(key)),
+            None => challenges.remove(domain),
+        };
+    }
+}
+
+/// Read a certificate chain and its private key from PEM files and check that
+/// they belong together
+pub fn load_certified_key(paths: &CertPaths) -> Result<CertifiedKey, String> {
+    let CertPaths { cert, key, ocsp } = paths;
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(|e| format!("unable to read \"{cert}\": {e}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate in \"{cert}\": {e}"))?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificates in \"{cert}\""));
+    }
+
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("unable to read key \"{key}\": {e}"))?;
+
+    let provider = CryptoProvider::get_default()
+        .expect("The crypto provider is installed before loading certificates");
+
+    let mut certified = CertifiedKey::from_der(certs, private_key, provider)
+        .map_err(|e| {
+            format!("unusable certificate \"{cert}\" and key \"{key}\": {e}")
+        })?;
+
+    if let Some(ocsp) = ocsp {
+        let response = std::fs::read(ocsp)
+            .map_err(|e| format!("unable to read \"{ocsp}\": {e}"))?;
+        certified.ocsp = Some(response);
+    }
+
+    Ok(certified)
+}
+
+/// Build the TLS config serving the certificates of `resolver` with the
+/// policy of the `[tls]` section, and log what that policy is
+pub fn server_config(
+    tls: &TlsConfig,
+    resolver: Arc<CertResolver>,
+) -> Result<ServerConfig, String> {
+    let default_provider = ring::default_provider();
+
+    let cipher_suites = if tls.cipher_suites.is_empty() {
+        default_provider.cipher_suites.clone()
+    } else {
+        tls.cipher_suites
+            .iter()
+            .map(|name| {
+                default_provider
+                    .cipher_suites
+                    .iter()
+                    .find(|suite| suite_name(suite) == *name)
+                    .copied()
+                    .ok_or_else(|| format!("unknown cipher suite \"{name}\""))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let versions: &[&SupportedProtocolVersion] = match tls.min_version {
+        TlsVersion::Tls12 => &[&version::TLS13, &version::TLS12],
+        TlsVersion::Tls13 => &[&version::TLS13],
+    };
+
+    // a version without any of the chosen suites can't be negotiated
+    let versions: Vec<_> = versions
+        .iter()
+        .copied()
+        .filter(|v| cipher_suites.iter().any(|s| s.version() == *v))
+        .collect();
+
+    let provider = CryptoProvider {
+        cipher_suites,
+        ..default_provider
+    };
+    let suite_names: Vec<_> =
+        provider.cipher_suites.iter().map(suite_name).collect();
+
+    let builder = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(&versions)
+        .map_err(|e| format!("unusable TLS versions and cipher suites: {e}"))?;
+
+    let builder = match &tls.client_ca {
+        Some(ca_path) => builder.with_client_cert_verifier(
+            client_verifier(ca_path)
+                .map_err(|e| format!("failed to load client CAs: {e}"))?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+
+    let stapled = resolver.paths().filter(|p| p.ocsp.is_some()).count();
+    let certificates = resolver.paths().count();
+
+    let mut config = builder.with_cert_resolver(resolver);
+
+    config.session_storage = match tls.session_cache_size {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+
+    if tls.session_tickets {
+        let rotator = TicketRotator::new(
+            tls.session_ticket_rotation,
+            TicketKey::generate,
+        )
+        .map_err(|e| format!("unable to create session tickets: {e}"))?;
+        config.ticketer = Arc::new(rotator);
+    }
+
+    let versions: Vec<_> = versions
+        .iter()
+        .map(|v| match v.version.as_str() {
+            Some(name) => name.replace('_', "."),
+            None => format!("{:?}", v.version),
+        })
+        .collect();
+
+    log::info!(
+        "TLS policy: versions {}; cipher suites {}; session cache {}; session \
+         tickets {}; OCSP stapled for {stapled} of {certificates} \
+         certificate(s); client certificates {}",
+        versions.join(", "),
+        suite_names.join(", "),
+        match tls.session_cache_size {
+            0 => "off".to_owned(),
+            size => format!("{size} entries"),
+        },
+        match tls.session_tickets {
+            true => format!(
+                "on, keys rotated every {}s",
+                tls.session_ticket_rotation
+            ),
+            false => "off".to_owned(),
+        },
+        match &tls.client_ca {
+            Some(ca_path) => format!("verified against \"{ca_path}\""),
+            None => "not requested".to_owned(),
+        },
+    );
+
+    Ok(config)
+}
+
+/// The name cipher suites are configured with, e.g.,
+/// "TLS13_AES_128_GCM_SHA256"
+fn suite_name(suite: &SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite())
+}
+
+/// Session tickets encrypted with one ChaCha20-Poly1305 key, until the
+/// [`TicketRotator`] replaces it with a new one. A ticket is the key's name,
+/// the nonce and the sealed session, with the name authenticated as well
+struct TicketKey {
+    name: [u8; 16],
+    key: aead::LessSafeKey,
+}
+
+impl TicketKey {
+    /// A new random key, as made by the rotator
+    fn generate() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+        let rng = SystemRandom::new();
+
+        let mut name = [0u8; 16];
+        let mut key = [0u8; 32];
+        rng.fill(&mut name).map_err(|_| GetRandomFailed)?;
+        rng.fill(&mut key).map_err(|_| GetRandomFailed)?;
+
+        // a 32 byte key always suits ChaCha20-Poly1305
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key)
+            .expect("The ticket key has the right length");
+
+        Ok(Box::new(Self {
+            name,
+            key: aead::LessSafeKey::new(key),
+        }))
+    }
+}
+
+impl std::fmt::Debug for TicketKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketKey")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl ProducesTickets for TicketKey {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    /// Keys don't expire by themselves. The rotator retires them and
+    /// reports the lifetime of its tickets
+    fn lifetime(&self) -> u32 {
+        0
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+
+        let mut sealed = plain.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(self.name),
+                &mut sealed,
+            )
+            .ok()?;
+
+        let mut ticket =
+            Vec::with_capacity(self.name.len() + nonce.len() + sealed.len());
+        ticket.extend(self.name);
+        ticket.extend(nonce);
+        ticket.extend(sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (name, rest) = cipher.split_at_checked(self.name.len())?;
+        let (nonce, sealed) = rest.split_at_checked(aead::NONCE_LEN)?;
+
+        // tickets from the other key the rotator holds are told apart by
+        // name, before spending any time on them
+        if name != self.name {
+            return None;
+        }
+
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut plain = sealed.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(self.name), &mut plain)
+            .ok()?
+            .len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}
+
+/// Verifies client certificates against the CAs in the PEM bundle `ca_path`.
+/// Clients without a certificate are let through
+pub fn client_verifier(
+    ca_path: &str,
+) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in CertificateDer::pem_file_iter(ca_path)
+        .map_err(|e| format!("unable to read \"{ca_path}\": {e}"))?
+    {
+        let cert = cert.map_err(|e| {
+            format!("invalid certificate in \"{ca_path}\": {e}")
+        })?;
+        roots
+            .add(cert)
+            .map_err(|e| format!("unusable CA in \"{ca_path}\": {e}"))?;
+    }
+
+    if roots.is_empty() {
+        return Err(format!("no certificates in \"{ca_path}\""));
+    }
+
+    WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()
+        .map_err(|e| format!("unusable client CAs \"{ca_path}\": {e}"))
+}
+
+/// The subject of the certificate a client authenticated with, if any. The
+/// chain has already been verified during the handshake
+pub fn client_cert(chain: Option<&[CertificateDer]>) -> Option<ClientCert> {
+    let end_entity = chain?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(end_entity).ok()?;
+
+    // header values can only hold visible ASCII, so anything else is escaped
+    let subject = cert
+        .subject()
+        .to_string()
+        .chars()
+        .flat_map(|c| match c {
+            ' '..='~' => vec![c],
+            _ => c.escape_unicode().collect(),
+        })
+        .collect();
+
+    Some(ClientCert(subject))
+}
+
+/// Reload the certificates into `resolver` whenever their PEM files change or
+/// SIGHUP is received
+pub fn watch_certificates(resolver: Arc<CertResolver>) {
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                log::warn!(
+                    "Unable to listen for SIGHUP. Certificates will only be \
+                     reloaded when their files change: \"{e}\""
+                );
+                None
+            }
+        };
+
+        let mut last_modified = modified_times(resolver.paths());
+
+        loop {
+            let hangup_recv = async {
+                match hangup.as_mut() {
+                    Some(h) => h.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = hangup_recv => {
+                    log::info!("Received SIGHUP. Reloading certificates");
+                }
+                _ = tokio::time::sleep(CERT_POLL_INTERVAL) => {
+                    let modified = modified_times(resolver.paths());
+                    if modified == last_modified {
+                        continue;
+                    }
+                    log::info!("Certificate files changed. Reloading");
+                }
+            }
+
+            // even if the reload fails, the files aren't tried again until
+            // they change once more
+            last_modified = modified_times(resolver.paths());
+
+            match resolver.reload() {
+                Ok(()) => log::info!(
+                    "Loaded {} certificate(s)",
+                    resolver.paths().count()
+                ),
+                Err(e) => log::error!(
+                    "Failed to reload certificates. Keeping the previous ones: \
+                     \"{e}\""
+                ),
+            }
+        }
+    });
+}
+
+fn modified_times<'a>(
+    paths: impl Iterator<Item = &'a CertPaths>,
+) -> Vec<Option<SystemTime>> {
+    let modified =
+        |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    paths
+        .flat_map(|p| {
+            [Some(&p.cert), Some(&p.key), p.ocsp.as_ref()]
+                .map(|path| path.and_then(|path| modified(path)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::io::DuplexStream;
+    use tokio_rustls::{
+        client,
+        rustls::{
+            client::WantsClientCert, pki_types::ServerName, ClientConfig,
+            ConfigBuilder,
+        },
+        server, TlsAcceptor, TlsConnector,
+    };
+
+    use super::*;
+    use crate::test_util;
+
+    /// Connect a client with `client` asking for `sni` to a server with
+    /// `server`. Returns the ends of the connection which completed the
+    /// handshake
+    async fn handshake(
+        server: ServerConfig,
+        client: ClientConfig,
+        sni: &str,
+    ) -> (
+        Option<client::TlsStream<DuplexStream>>,
+        Option<server::TlsStream<DuplexStream>>,
+    ) {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let acceptor = TlsAcceptor::from(Arc::new(server));
+        let connector = TlsConnector::from(Arc::new(client));
+        let server_name = ServerName::try_from(sni.to_owned()).unwrap();
+
+        let (client, server) = tokio::join!(
+            connector.connect(server_name, client_io),
+            acceptor.accept(server_io),
+        );
+        (client.ok(), server.ok())
+    }
+
+    /// A client which trusts `trusted`
+    fn client_config(
+        trusted: &[&CertificateDer<'static>],
+    ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        let mut roots = RootCertStore::empty();
+        for cert in trusted {
+            roots.add((*cert).clone()).unwrap();
+        }
+        ClientConfig::builder().with_root_certificates(roots)
+    }
+
+    /// The certificate a client asking for `sni` is shown by a server with
+    /// `config`. `trusted` are the certificates the client accepts
+    async fn presented(
+        config: ServerConfig,
+        sni: &str,
+        trusted: &[&CertificateDer<'static>],
+    ) -> CertificateDer<'static> {
+        let client = client_config(trusted).with_no_client_auth();
+        let (stream, _) = handshake(config, client, sni).await;
+
+        let (_, connection) = stream.unwrap().into_inner();
+        connection.peer_certificates().unwrap()[0].clone()
+    }
+
+    /// The subject of the client certificate a server requesting ones from
+    /// the CAs in `ca_path` sees, or `Err` if the handshake failed.
+    /// `client_auth` is the client's certificate chain and key
+    async fn client_subject(
+        dir: &Path,
+        ca_path: &str,
+        client_auth: Option<(
+            Vec<CertificateDer<'static>>,
+            PrivateKeyDer<'static>,
+        )>,
+    ) -> Result<Option<String>, ()> {
+        let (server_cert, server_der) =
+            test_util::self_signed(dir, "server", &["slot.example"]);
+        let resolver =
+            Arc::new(CertResolver::load(server_cert, Vec::new()).unwrap());
+        let tls = TlsConfig {
+            client_ca: Some(ca_path.to_owned()),
+            ..Default::default()
+        };
+        let server = server_config(&tls, resolver).unwrap();
+
+        let client = client_config(&[&server_der]);
+        let client = match client_auth {
+            Some((chain, key)) => {
+                client.with_client_auth_cert(chain, key).unwrap()
+            }
+            None => client.with_no_client_auth(),
+        };
+
+        let (_, stream) = handshake(server, client, "slot.example").await;
+        let (_, connection) = stream.ok_or(())?.into_inner();
+        Ok(client_cert(connection.peer_certificates()).map(|c| c.0))
+    }
+
+    #[test]
+    fn certificate_is_chosen_by_san() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let (default, default_der) =
+            test_util::self_signed(dir, "default", &["default.example"]);
+        let (exact, exact_der) =
+            test_util::self_signed(dir, "exact", &["www.example.com"]);
+        let (wildcard, wildcard_der) = test_util::self_signed(
+            dir,
+            "wildcard",
+            &["*.example.com", "example.org"],
+        );
+        let (shadowed, _) =
+            test_util::self_signed(dir, "shadowed", &["example.org"]);
+
+        let certs =
+            CertSet::load(&default, &[exact, wildcard, shadowed]).unwrap();
+        let selected = |name| certs.select(name).cert[0].clone();
+
+        assert_eq!(selected(Some("www.example.com")), exact_der);
+        assert_eq!(selected(Some("WWW.Example.com")), exact_der);
+        assert_eq!(selected(Some("api.example.com")), wildcard_der);
+        assert_eq!(selected(Some("example.org")), wildcard_der);
+
+        // a wildcard covers one label only
+        assert_eq!(selected(Some("a.b.example.com")), default_der);
+        assert_eq!(selected(Some("example.com")), default_der);
+        assert_eq!(selected(None), default_der);
+    }
+
+    #[test]
+    fn certificate_without_names_is_never_chosen() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let (default, default_der) =
+            test_util::self_signed(dir, "default", &["default.example"]);
+        let (nameless, _) = test_util::self_signed(dir, "nameless", &[]);
+
+        let certs = CertSet::load(&default, &[nameless]).unwrap();
+        assert!(certs.by_name.is_empty());
+        assert_eq!(certs.select(Some("nameless")).cert[0], default_der);
+    }
+
+    #[test]
+    fn unparsable_certificate_is_named_in_the_error() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let (paths, _) =
+            test_util::self_signed(dir.path(), "blog", &["blog.example"]);
+
+        // a key which doesn't check the certificate, holding one which isn't
+        // DER at all
+        let provider = CryptoProvider::get_default().unwrap();
+        let private_key = PrivateKeyDer::from_pem_file(&paths.key).unwrap();
+        let signing_key =
+            provider.key_provider.load_private_key(private_key).unwrap();
+        let garbage = CertificateDer::from(b"not a certificate".to_vec());
+        let key = CertifiedKey::new(vec![garbage], signing_key);
+
+        let err = dns_names(&key, &paths.cert).unwrap_err();
+        assert!(err.contains(&paths.cert), "{err}");
+    }
+
+    #[tokio::test]
+    async fn handshake_presents_certificate_for_sni() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let (default, default_der) =
+            test_util::self_signed(dir, "default", &["default.example"]);
+        let (blog, blog_der) =
+            test_util::self_signed(dir, "blog", &["blog.example.com"]);
+        let trusted = [&default_der, &blog_der];
+
+        let resolver =
+            Arc::new(CertResolver::load(default, vec![blog]).unwrap());
+        let config =
+            || server_config(&TlsConfig::default(), resolver.clone()).unwrap();
+
+        let shown = presented(config(), "blog.example.com", &trusted).await;
+        assert_eq!(shown, blog_der);
+
+        let shown = presented(config(), "default.example", &trusted).await;
+        assert_eq!(shown, default_der);
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_new_certificates() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let (paths, old_der) =
+            test_util::self_signed(dir, "slot", &["slot.example"]);
+        let resolver = Arc::new(CertResolver::load(paths, Vec::new()).unwrap());
+        let config =
+            || server_config(&TlsConfig::default(), resolver.clone()).unwrap();
+
+        let (_, new_der) =
+            test_util::self_signed(dir, "slot", &["slot.example"]);
+        resolver.reload().unwrap();
+
+        let trusted = [&old_der, &new_der];
+        let shown = presented(config(), "slot.example", &trusted).await;
+        assert_eq!(shown, new_der);
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_the_old_certificate() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let (paths, der) =
+            test_util::self_signed(dir, "slot", &["slot.example"]);
+        let resolver =
+            Arc::new(CertResolver::load(paths.clone(), Vec::new()).unwrap());
+        let config =
+            || server_config(&TlsConfig::default(), resolver.clone()).unwrap();
+
+        std::fs::write(&paths.cert, "garbage").unwrap();
+        let err = resolver.reload().unwrap_err();
+        assert!(err.contains(&paths.cert), "{err}");
+
+        let shown = presented(config(), "slot.example", &[&der]).await;
+        assert_eq!(shown, der);
+    }
+
+    #[tokio::test]
+    async fn client_cert_from_trusted_ca_is_verified() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let (ca, ca_path) = test_util::ca(dir.path(), "ca");
+        let cert = test_util::client_cert(&ca, "laptop");
+
+        let subject = client_subject(dir.path(), &ca_path, Some(cert)).await;
+        assert_eq!(subject, Ok(Some("CN=laptop".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn client_cert_from_untrusted_ca_is_refused() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let (_, ca_path) = test_util::ca(dir.path(), "ca");
+        let (other_ca, _) = test_util::ca(dir.path(), "other");
+        let cert = test_util::client_cert(&other_ca, "intruder");
+
+        let subject = client_subject(dir.path(), &ca_path, Some(cert)).await;
+        assert_eq!(subject, Err(()));
+    }
+
+    #[tokio::test]
+    async fn client_without_cert_is_let_through() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let (_, ca_path) = test_util::ca(dir.path(), "ca");
+
+        let subject = client_subject(dir.path(), &ca_path, None).await;
+        assert_eq!(subject, Ok(None));
+    }
+
+    #[test]
+    fn ticket_keys_only_open_their_own_tickets() {
+        let key = TicketKey::generate().unwrap();
+        let other = TicketKey::generate().unwrap();
+
+        let ticket = key.encrypt(b"session").unwrap();
+        assert_eq!(key.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+        assert_eq!(other.decrypt(&ticket), None);
+
+        // the same session never gives the same ticket twice
+        assert_ne!(key.encrypt(b"session").unwrap(), ticket);
+
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(key.decrypt(&tampered), None);
+
+        assert_eq!(key.decrypt(&ticket[..20]), None);
+        assert_eq!(key.decrypt(&[]), None);
+    }
+
+    #[test]
+    fn tickets_are_rotated_once() {
+        test_util::install_crypto_provider();
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = test_util::self_signed(dir.path(), "cert", &["a.b"]);
+        let resolver = Arc::new(CertResolver::load(cert, Vec::new()).unwrap());
+
+        let tls = TlsConfig {
+            session_tickets: true,
+            session_ticket_rotation: 60,
+            ..Default::default()
+        };
+        let config = server_config(&tls, resolver).unwrap();
+
+        // tickets outlive the key that made them by one rotation at most
+        assert_eq!(config.ticketer.lifetime(), 120);
+
+        let ticket = config.ticketer.encrypt(b"session").unwrap();
+        let session = config.ticketer.decrypt(&ticket);
+        assert_eq!(session.as_deref(), Some(&b"session"[..]));
+    }
+}
@@ -0,0 +1,353 @@
+//! Proxying of HTTP/1.1 protocol upgrades (e.g., WebSockets) to modules
+//!
+//! reqwest can't carry an upgrade handshake, so upgrade requests get their own
+//! connection to the module. Once both the client and the module have switched
+//! protocols, bytes are copied between them until either side closes.
+//!
+//! Requests to modules serving HTTP on a Unix socket, which reqwest can't
+//! reach either, also get a connection of their own.
+
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue},
+    response::Response,
+};
+use hyper::client::conn::http1::SendRequest;
+use hyper_util::rt::TokioIo;
+use reqwest::StatusCode;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+};
+
+use crate::{
+    error::ProxyError,
+    forward::{end_to_end_headers, set_forwarding_headers},
+    listener::ConnectionPermit,
+    store::HttpAddr,
+};
+
+/// Whether the client asked to switch protocols on this connection
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("upgrade"));
+
+    connection_upgrade && headers.contains_key(header::UPGRADE)
+}
+
+/// Carry out the upgrade handshake with the module at `http_addr` and, if the
+/// module agrees, tunnel the upgraded connection back to the client. The
+/// client's forwarding headers are replaced with `forwarding`
+pub async fn forward_upgrade(
+    modname: String,
+    http_addr: &HttpAddr,
+    path_and_query: String,
+    forwarding: HeaderMap,
+    connect_timeout: Duration,
+    mut req: Request,
+) -> Result<Response, ProxyError> {
+    let client_upgrade = hyper::upgrade::on(&mut req);
+
+    // the tunnel outlives the client's connection, so it holds the
+    // connection's place under the limit itself
+    let permit = req.extensions_mut().remove::<ConnectionPermit>();
+
+    let (parts, body) = req.into_parts();
+
+    let upgrade = parts.headers.get(header::UPGRADE).cloned();
+
+    // the hop-by-hop filter removes the upgrade headers, so they're put back
+    // for the module's end of the connection
+    let mut headers = end_to_end_headers(&parts.headers);
+    set_forwarding_headers(&mut headers, forwarding);
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    if let Some(upgrade) = upgrade {
+        headers.insert(header::UPGRADE, upgrade);
+    }
+    if let Ok(host) = HeaderValue::from_str(&http_addr.authority()) {
+        headers.insert(header::HOST, host);
+    }
+
+    let mut mod_req = Request::new(body);
+    *mod_req.method_mut() = parts.method;
+    *mod_req.headers_mut() = headers;
+    *mod_req.uri_mut() = path_and_query
+        .parse()
+        .map_err(|e| ProxyError::BadResponse(format!("invalid path: {e}")))?;
+
+    let mut sender = connect(http_addr, connect_timeout).await?;
+
+    let mut mod_resp = sender
+        .send_request(mod_req)
+        .await
+        .map_err(|e| ProxyError::BadResponse(e.to_string()))?;
+
+    // the module declined to switch protocols. pass its answer on as-is
+    if mod_resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        let (mut parts, body) = mod_resp.into_parts();
+        parts.headers = end_to_end_headers(&parts.headers);
+        return Ok(Response::from_parts(parts, Body::new(body)));
+    }
+
+    let module_upgrade = hyper::upgrade::on(&mut mod_resp);
+
+    tokio::spawn(async move {
+        let _permit = permit;
+
+        let (client, module) =
+            match tokio::try_join!(client_upgrade, module_upgrade) {
+                Ok(c) => c,
+                Err(e) => {
+                    log::warn!(
+                        "Upgrade with module \"{modname}\" failed: \"{e}\""
+                    );
+                    return;
+                }
+            };
+
+        log::debug!("Tunnel to module \"{modname}\" established");
+
+        let mut client = TokioIo::new(client);
+        let mut module = TokioIo::new(module);
+
+        match tokio::io::copy_bidirectional(&mut client, &mut module).await {
+            Ok((to_module, to_client)) => log::debug!(
+                "Tunnel to module \"{modname}\" closed. {to_module} bytes \
+                 sent, {to_client} bytes received"
+            ),
+            Err(e) => log::debug!(
+                "Tunnel to module \"{modname}\" closed with error: \"{e}\""
+            ),
+        }
+    });
+
+    // the switching protocols response keeps its upgrade headers since they
+    // are what tells the client the upgrade happened
+    let mut resp = Response::builder().status(StatusCode::SWITCHING_PROTOCOLS);
+
+    if let Some(headers) = resp.headers_mut() {
+        *headers = end_to_end_headers(mod_resp.headers());
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        if let Some(upgrade) = mod_resp.headers().get(header::UPGRADE) {
+            headers.insert(header::UPGRADE, upgrade.clone());
+        }
+    }
+
+    resp.body(Body::empty())
+        .map_err(|e| ProxyError::BadResponse(e.to_string()))
+}
+
+/// Open a connection to the module at `http_addr` for requests which can't go
+/// through reqwest. The connection allows upgrades. A Unix socket is only used
+/// while it is still served by the module's user, as the module may have left
+/// and another user taken over the path since it joined
+pub async fn connect(
+    http_addr: &HttpAddr,
+    connect_timeout: Duration,
+) -> Result<SendRequest<Body>, ProxyError> {
+    let unreachable =
+        |e: std::io::Error| ProxyError::Unreachable(e.to_string());
+
+    let connecting = async {
+        match http_addr {
+            HttpAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr).await;
+                handshake(stream.map_err(unreachable)?).await
+            }
+            HttpAddr::Unix { path, uid } => {
+                let stream = UnixStream::connect(path).await;
+                let stream = stream.map_err(unreachable)?;
+
+                let peer_uid = stream.peer_cred().map_err(unreachable)?.uid();
+                if peer_uid != *uid {
+                    return Err(ProxyError::Unreachable(format!(
+                        "socket \"{}\" is served by user {peer_uid} instead \
+                         of the module's user {uid}",
+                        path.display()
+                    )));
+                }
+
+                handshake(stream).await
+            }
+        }
+    };
+
+    tokio::time::timeout(connect_timeout, connecting)
+        .await
+        .map_err(|_| ProxyError::Timeout)?
+}
+
+async fn handshake<S>(stream: S) -> Result<SendRequest<Body>, ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (sender, conn) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| ProxyError::Unreachable(e.to_string()))?;
+
+    tokio::spawn(async move {
+        if let Err(e) = conn.with_upgrades().await {
+            log::debug!("Connection to module closed: \"{e}\"");
+        }
+    });
+
+    Ok(sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use axum::{
+        extract::ws::{WebSocket, WebSocketUpgrade},
+        routing::get,
+        Router,
+    };
+    use futures::{SinkExt, StreamExt};
+    use slot_client::protocol::JoinOptions;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::{config::Config, listener, test_util};
+
+    async fn echo(mut socket: WebSocket) {
+        while let Some(Ok(msg)) = socket.recv().await {
+            if socket.send(msg).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Slot with a module named "mod" serving `routes`
+    async fn slot_with(routes: Router) -> Router {
+        test_util::slot_with_module(
+            routes,
+            JoinOptions::default(),
+            Config::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn websocket_echo_round_trip() {
+        let routes = Router::new().route(
+            "/mod/ws",
+            get(async |ws: WebSocketUpgrade| ws.on_upgrade(echo)),
+        );
+        let slot = test_util::serve(slot_with(routes).await).await;
+
+        let (mut ws, resp) =
+            tokio_tungstenite::connect_async(format!("ws://{slot}/mod/ws"))
+                .await
+                .unwrap();
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        for text in ["hello", "world"] {
+            ws.send(tungstenite::Message::text(text)).await.unwrap();
+            let reply = ws.next().await.unwrap().unwrap();
+            assert_eq!(reply.into_text().unwrap().as_str(), text);
+        }
+
+        ws.close(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socket_of_another_user_is_not_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("http.sock");
+        test_util::serve_unix(test_util::echo_target(), &socket);
+        let uid = std::fs::metadata(&socket).unwrap().uid();
+
+        let http_addr = HttpAddr::Unix {
+            path: socket.clone(),
+            uid,
+        };
+        let timeout = Duration::from_secs(1);
+        assert!(connect(&http_addr, timeout).await.is_ok());
+
+        let http_addr = HttpAddr::Unix {
+            path: socket,
+            uid: uid + 1,
+        };
+        assert!(matches!(
+            connect(&http_addr, timeout).await,
+            Err(ProxyError::Unreachable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn tunnel_counts_towards_connection_limit() {
+        let routes = Router::new().route(
+            "/mod/ws",
+            get(async |ws: WebSocketUpgrade| ws.on_upgrade(echo)),
+        );
+        let slot = slot_with(routes).await;
+
+        let args = test_util::args(&["--max-connections", "1"]);
+        let settings = listener::Settings::new(&args, &Default::default());
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap();
+        tokio::spawn(listener::serve_http(addr, slot, settings.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (mut ws, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/mod/ws"))
+                .await
+                .unwrap();
+        ws.send(tungstenite::Message::text("hello")).await.unwrap();
+        ws.next().await.unwrap().unwrap();
+
+        // the HTTP connection is over, but its tunnel still holds the only
+        // place, so another client isn't served
+        let other = reqwest::Client::new().get(format!("http://{addr}/mod/"));
+        let other = tokio::spawn(other.send());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!other.is_finished());
+
+        ws.close(None).await.unwrap();
+        drop(ws);
+
+        let resp = tokio::time::timeout(Duration::from_secs(5), other).await;
+        assert!(resp.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn declined_upgrade_is_passed_on() {
+        let routes = Router::new().route("/mod/ws", get(async || "no upgrade"));
+        let slot = slot_with(routes).await;
+
+        let req = Request::get("/mod/ws")
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert!(is_upgrade_request(req.headers()));
+
+        let resp = test_util::send(&slot, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(header::UPGRADE));
+        assert_eq!(test_util::body_text(resp).await, "no upgrade");
+    }
+
+    #[test]
+    fn upgrade_needs_both_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        assert!(!is_upgrade_request(&headers));
+
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, Upgrade"),
+        );
+        assert!(is_upgrade_request(&headers));
+    }
+}
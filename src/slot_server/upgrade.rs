@@ -1,18 +1,62 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
-    handler::HandlerWithoutStateExt,
-    http::{uri::Authority, StatusCode, Uri},
-    response::Redirect,
-    BoxError,
+    extract::Request,
+    http::{
+        header::STRICT_TRANSPORT_SECURITY, uri::Authority, HeaderValue,
+        StatusCode, Uri,
+    },
+    response::{IntoResponse, Redirect, Response},
+    BoxError, Router,
 };
 use axum_extra::extract::Host;
+use tower::ServiceExt;
 
-use crate::cli::Args;
+use crate::{
+    acme::{self, Http01Tokens},
+    cli::Args,
+    config::{HstsConfig, RedirectConfig},
+    listener,
+};
+
+/// The port browsers use for HTTPS when the URL has none
+const DEFAULT_HTTPS_PORT: u16 = 443;
 
 /// An independent webserver that only serves to redirect clients to the main
-/// webserver using HTTPS
-pub async fn redirect_http_to_https(args: Args) {
+/// webserver using HTTPS. It also answers ACME HTTP-01 challenges, which must
+/// be served over plain HTTP, and serves `routes` for the paths excluded from
+/// the redirect
+pub async fn redirect_http_to_https(
+    args: Args,
+    config: RedirectConfig,
+    acme_tokens: Http01Tokens,
+    routes: Router,
+    settings: listener::Settings,
+) {
+    let https_port = config.port.unwrap_or(args.https_port);
+
+    if !config.exclude.is_empty() {
+        log::warn!(
+            "Serving paths starting with {:?} over plain HTTP",
+            config.exclude
+        );
+    }
+
+    let routes = redirect_routes(config, https_port, acme_tokens, routes);
+
+    let addr = SocketAddr::new(args.web_addr, args.http_port);
+    log::info!("Redirecting HTTP to HTTPS on port {https_port}");
+    listener::serve_http(addr, routes, settings).await;
+}
+
+/// Routes redirecting to HTTPS on `https_port`, except for ACME challenges
+/// and the paths `config` excludes, which are served by `routes`
+fn redirect_routes(
+    config: RedirectConfig,
+    https_port: u16,
+    acme_tokens: Http01Tokens,
+    routes: Router,
+) -> Router {
     fn make_https(
         host: &str,
         uri: Uri,
@@ -37,28 +81,128 @@ pub async fn redirect_http_to_https(args: Args) {
             None => authority.as_str(),
         };
 
-        parts.authority = Some(format!("{bare_host}:{https_port}").parse()?);
+        parts.authority = Some(match https_port {
+            DEFAULT_HTTPS_PORT => bare_host.parse()?,
+            _ => format!("{bare_host}:{https_port}").parse()?,
+        });
 
         Ok(Uri::from_parts(parts)?)
     }
 
-    let redirect = move |Host(host): Host, uri: Uri| async move {
-        match make_https(&host, uri, args.https_port) {
-            Ok(uri) => Ok(Redirect::permanent(&uri.to_string())),
+    let config = Arc::new(config);
+
+    let redirect = move |Host(host): Host, req: Request| async move {
+        let path = req.uri().path();
+        if config.exclude.iter().any(|prefix| path.starts_with(prefix)) {
+            let Ok(resp) = routes.oneshot(req).await;
+            return resp;
+        }
+
+        match make_https(&host, req.uri().clone(), https_port) {
+            Ok(uri) if config.permanent => {
+                Redirect::permanent(&uri.to_string()).into_response()
+            }
+            Ok(uri) => Redirect::temporary(&uri.to_string()).into_response(),
             Err(e) => {
                 log::warn!("Failed to convert URI to HTTPS: \"{e}\"");
-                Err(StatusCode::BAD_REQUEST)
+                StatusCode::BAD_REQUEST.into_response()
             }
         }
     };
 
-    let addr = SocketAddr::new(args.web_addr, args.http_port);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    log::info!(
-        "HTTP redirect server listening on {}",
-        listener.local_addr().unwrap()
-    );
-    axum::serve(listener, redirect.into_make_service())
-        .await
-        .unwrap();
+    acme::challenge_routes(acme_tokens).fallback(redirect)
+}
+
+/// Add the `Strict-Transport-Security` header described by `config` to every
+/// response of `routes`, if it has a max age
+pub fn strict_transport_security(
+    routes: Router,
+    config: &HstsConfig,
+) -> Router {
+    let Some(max_age) = config.max_age else {
+        return routes;
+    };
+
+    let mut value = format!("max-age={max_age}");
+    if config.include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+    if config.preload {
+        value.push_str("; preload");
+    }
+
+    let value = HeaderValue::from_str(&value)
+        .expect("The Strict-Transport-Security value is always valid");
+
+    routes.layer(axum::middleware::map_response(move |mut resp: Response| {
+        resp.headers_mut()
+            .insert(STRICT_TRANSPORT_SECURITY, value.clone());
+        async { resp }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::header::{HOST, LOCATION},
+    };
+
+    use super::*;
+    use crate::test_util;
+
+    /// Redirect routes excluding `exclude`, which serve "module" otherwise
+    fn routes(exclude: &[&str], https_port: u16) -> Router {
+        let config = RedirectConfig {
+            exclude: exclude.iter().map(|e| e.to_string()).collect(),
+            ..Default::default()
+        };
+        let tokens = Http01Tokens::default();
+        tokens
+            .write()
+            .unwrap()
+            .insert("token".to_owned(), "key-auth".to_owned());
+
+        let module = Router::new().fallback(async || "module");
+        redirect_routes(config, https_port, tokens, module)
+    }
+
+    async fn get(routes: &Router, uri: &str) -> Response {
+        let req = Request::get(uri)
+            .header(HOST, "example.com:8080")
+            .body(Body::empty())
+            .unwrap();
+        test_util::send(routes, req).await
+    }
+
+    #[tokio::test]
+    async fn requests_are_redirected() {
+        let resp = get(&routes(&[], 443), "/mod/a?b=c").await;
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers()[LOCATION], "https://example.com/mod/a?b=c");
+
+        let resp = get(&routes(&[], 8443), "/").await;
+        assert_eq!(resp.headers()[LOCATION], "https://example.com:8443/");
+    }
+
+    #[tokio::test]
+    async fn only_excluded_paths_are_served() {
+        let routes = routes(&["/public/"], 443);
+
+        let resp = get(&routes, "/public/a").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test_util::body_text(resp).await, "module");
+
+        let resp = get(&routes, "/publicity").await;
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn acme_challenges_are_answered() {
+        let routes = routes(&[], 443);
+
+        let resp = get(&routes, "/.well-known/acme-challenge/token").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test_util::body_text(resp).await, "key-auth");
+    }
 }